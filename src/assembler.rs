@@ -0,0 +1,333 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use log::info;
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::chainparams::params;
+use crate::errors::Result;
+use crate::mempool::Mempool;
+use crate::transaction::Transaction;
+
+/// BlockAssembler builds the block templates of a mining node from the mempool.
+///
+/// It copies what it needs from the chain and the mempool when it is created,
+/// so that the transactions are selected without holding them.
+pub struct BlockAssembler {
+    /// template is the block on the tip with the coinbase alone
+    template: Block,
+    address: String,
+    entries: HashMap<String, Entry>,
+}
+
+/// Entry is a mempool transaction waiting to be selected
+struct Entry {
+    tx: Transaction,
+    fee: i64,
+    size: usize,
+    parents: HashSet<String>,
+}
+
+/// Package is the fee and the size of a transaction with its ancestors not in the block yet
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Package {
+    fee: i64,
+    size: usize,
+}
+
+/// Candidate is a package in the queue of select_packages, the best one has the highest
+/// fee rate and ties go to the smallest txid
+struct Candidate {
+    package: Package,
+    txid: String,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let rate = self.package.fee as i128 * other.package.size as i128;
+        let other_rate = other.package.fee as i128 * self.package.size as i128;
+        rate.cmp(&other_rate)
+            .then_with(|| other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl BlockAssembler {
+    pub fn new(
+        blockchain: &Blockchain,
+        mempool: &Mempool,
+        address: &str,
+    ) -> Result<BlockAssembler> {
+        let height = (blockchain.get_best_height()? + 1) as usize;
+        let coinbase = Transaction::new_coinbase(address.to_string(), String::new(), height, 0)?;
+        let template = blockchain.new_block_template(vec![coinbase])?;
+
+        let mut entries = HashMap::new();
+        for tx in mempool.get_transactions() {
+            let entry = Entry {
+                fee: mempool.get_fee(&tx.id),
+                size: tx.get_size()?,
+                parents: mempool.get_parents(&tx),
+                tx,
+            };
+            entries.insert(entry.tx.id.clone(), entry);
+        }
+        Ok(BlockAssembler {
+            template,
+            address: address.to_string(),
+            entries,
+        })
+    }
+
+    /// CreateNewBlock returns a template on the tip, ready to be mined, that pays
+    /// the reward to the given address.
+    ///
+    /// Transactions are picked by the fee per byte of their package: the transaction
+    /// together with its unconfirmed ancestors not in the block yet, so that a child
+    /// can pay for its parent. Parents always come before their children and the
+    /// block stays under max_block_size.
+    pub fn create_new_block(mut self) -> Result<Block> {
+        // the fees do not change the size of the coinbase, the template
+        // with the coinbase alone is the room left for the transactions
+        let mut size = self.template.get_size()?;
+        let room = params().max_block_size.saturating_sub(size);
+        let selected = select_packages(&self.entries, room);
+        size += selected
+            .iter()
+            .map(|txid| self.entries[txid].size)
+            .sum::<usize>();
+
+        let header = self.template.get_header();
+        let fees: i64 = selected.iter().map(|txid| self.entries[txid].fee).sum();
+        let mut txs = vec![Transaction::new_coinbase(
+            self.address.clone(),
+            String::new(),
+            header.height,
+            fees as i32,
+        )?];
+        for txid in &selected {
+            if let Some(entry) = self.entries.remove(txid) {
+                txs.push(entry.tx);
+            }
+        }
+        info!(
+            "new block template at height {} with {} transactions paying {} in fees, {} bytes",
+            header.height,
+            selected.len(),
+            fees,
+            size
+        );
+        let mut block = Block::new_template(
+            txs,
+            header.prev_block_hash.clone(),
+            header.height,
+            header.bits,
+        )?;
+        block.set_timestamp(header.timestamp);
+        Ok(block)
+    }
+}
+
+/// SelectPackages returns the ids of the transactions to put in a block with room
+/// bytes left, picking first the packages that pay the most per byte.
+///
+/// The packages are computed once and updated as their ancestors get in the block,
+/// a queue keeps the best one on top and drops the outdated copies when they come up.
+fn select_packages(entries: &HashMap<String, Entry>, mut room: usize) -> Vec<String> {
+    let mut packages = HashMap::new();
+    let mut descendants: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for txid in entries.keys() {
+        let mut package = Package {
+            fee: entries[txid].fee,
+            size: entries[txid].size,
+        };
+        for ancestor in get_ancestors(entries, txid) {
+            package.fee += entries[ancestor].fee;
+            package.size += entries[ancestor].size;
+            descendants.entry(ancestor).or_default().push(txid);
+        }
+        packages.insert(txid.as_str(), package);
+        queue.push(Candidate {
+            package,
+            txid: txid.clone(),
+        });
+    }
+
+    let mut selected = Vec::new();
+    let mut in_block = HashSet::new();
+    while let Some(candidate) = queue.pop() {
+        let txid = candidate.txid.as_str();
+        if in_block.contains(txid) || packages.get(txid) != Some(&candidate.package) {
+            continue;
+        }
+        if candidate.package.size > room {
+            // the package does not fit, the transaction it was built for is left out
+            packages.remove(txid);
+            continue;
+        }
+        room -= candidate.package.size;
+        for id in get_package(entries, &in_block, txid) {
+            // the packages of its descendants do not carry it anymore
+            let entry = &entries[&id];
+            for descendant in descendants.get(id.as_str()).into_iter().flatten() {
+                if let Some(package) = packages.get_mut(descendant) {
+                    package.fee -= entry.fee;
+                    package.size -= entry.size;
+                    queue.push(Candidate {
+                        package: *package,
+                        txid: descendant.to_string(),
+                    });
+                }
+            }
+            in_block.insert(id.clone());
+            selected.push(id);
+        }
+    }
+    selected
+}
+
+/// GetAncestors returns the unconfirmed transactions a transaction depends on
+fn get_ancestors<'a>(entries: &'a HashMap<String, Entry>, txid: &str) -> HashSet<&'a str> {
+    let mut ancestors = HashSet::new();
+    let mut stack = vec![txid];
+    while let Some(id) = stack.pop() {
+        if let Some(entry) = entries.get(id) {
+            for parent in &entry.parents {
+                if let Some((parent, _)) = entries.get_key_value(parent) {
+                    if ancestors.insert(parent.as_str()) {
+                        stack.push(parent.as_str());
+                    }
+                }
+            }
+        }
+    }
+    ancestors
+}
+
+/// GetPackage returns a transaction and its ancestors that are not in the block yet,
+/// every parent before its children
+fn get_package(
+    entries: &HashMap<String, Entry>,
+    in_block: &HashSet<String>,
+    txid: &str,
+) -> Vec<String> {
+    let mut package = Vec::new();
+    let mut visited = HashSet::new();
+    add_ancestors_first(entries, in_block, txid, &mut visited, &mut package);
+    package
+}
+
+fn add_ancestors_first(
+    entries: &HashMap<String, Entry>,
+    in_block: &HashSet<String>,
+    txid: &str,
+    visited: &mut HashSet<String>,
+    package: &mut Vec<String>,
+) {
+    if in_block.contains(txid) || !visited.insert(txid.to_string()) {
+        return;
+    }
+    if let Some(entry) = entries.get(txid) {
+        let mut parents: Vec<&String> = entry.parents.iter().collect();
+        parents.sort();
+        for parent in parents {
+            add_ancestors_first(entries, in_block, parent, visited, package);
+        }
+        package.push(txid.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, fee: i64, size: usize, parents: &[&str]) -> (String, Entry) {
+        let tx = Transaction {
+            id: id.to_string(),
+            vin: Vec::new(),
+            vout: Vec::new(),
+        };
+        let parents = parents.iter().map(|p| p.to_string()).collect();
+        (
+            id.to_string(),
+            Entry {
+                tx,
+                fee,
+                size,
+                parents,
+            },
+        )
+    }
+
+    #[test]
+    fn test_select_packages() {
+        let entries: HashMap<String, Entry> = [
+            entry("parent", 1, 100, &[]),
+            entry("child", 50, 100, &["parent"]),
+            entry("other", 10, 100, &[]),
+            entry("large", 1000, 1000, &[]),
+        ]
+        .into_iter()
+        .collect();
+
+        // the child pays for its parent, which has to come first
+        assert_eq!(
+            select_packages(&entries, 10_000),
+            vec!["large", "parent", "child", "other"]
+        );
+        // what does not fit is left out, smaller packages still get in
+        assert_eq!(select_packages(&entries, 250), vec!["parent", "child"]);
+        assert_eq!(select_packages(&entries, 150), vec!["other"]);
+        assert!(select_packages(&entries, 50).is_empty());
+    }
+
+    #[test]
+    fn test_update_packages() {
+        let entries: HashMap<String, Entry> = [
+            entry("a", 100, 100, &[]),
+            entry("b", 1, 100, &["a"]),
+            entry("c", 60, 100, &["a"]),
+            entry("d", 30, 100, &[]),
+            entry("e", 40, 100, &["b", "c"]),
+        ]
+        .into_iter()
+        .collect();
+
+        // once a is in the block, b and e only pay for themselves and d goes first
+        assert_eq!(
+            select_packages(&entries, 10_000),
+            vec!["a", "c", "d", "b", "e"]
+        );
+        // e brings b along, there is no room left for either
+        assert_eq!(select_packages(&entries, 300), vec!["a", "c", "d"]);
+    }
+
+    #[test]
+    fn test_no_child_without_its_parent() {
+        let entries: HashMap<String, Entry> = [
+            entry("parent", 1, 500, &[]),
+            entry("child", 100, 10, &["parent"]),
+            entry("other", 10, 100, &[]),
+        ]
+        .into_iter()
+        .collect();
+
+        // the parent does not fit, its child cannot be mined alone
+        assert_eq!(select_packages(&entries, 400), vec!["other"]);
+    }
+}
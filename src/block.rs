@@ -1,20 +1,64 @@
-use std::{time::SystemTime, vec};
+use std::{sync::atomic::AtomicBool, time::SystemTime, vec};
 
-use crate::{errors::Result, transaction::Transaction};
+use crate::chainparams::{params, ChainParams};
+use crate::miner::Miner;
+use crate::pow::{compact_to_target, get_work, U256};
+use crate::{errors::Result, transaction::Transaction, txs::TXOutput};
 use crypto::{digest::Digest, sha2::Sha256};
-use log::info;
+use failure::format_err;
 use merkle_cbt::merkle_tree::{Merge, CBMT};
 use serde::{Deserialize, Serialize};
 
-const TARGET_HEXT: usize = 4;
+const BLOCK_VERSION: i32 = 1;
+
+/// BlockHeader is the part of a block covered by the proof of work,
+/// it commits to the transactions through the merkle root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub timestamp: u128,
+    pub bits: u32,
+    pub nonce: u32,
+    pub height: usize,
+}
+
+impl BlockHeader {
+    /// Hash returns the hash of the header, which is the hash of the block
+    pub fn hash(&self) -> Result<String> {
+        let data = bincode::serialize(self)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        Ok(hasher.result_str())
+    }
+
+    /// CheckProofOfWork checks the hash of the header against the target encoded
+    /// in its bits, without needing the transactions
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        let target = compact_to_target(self.bits)?;
+        if target.is_zero() || target > compact_to_target(params().pow_limit_bits)? {
+            return Ok(false);
+        }
+        self.meets_target(&target)
+    }
+
+    /// MeetsTarget tells whether the hash of the header is not above the target
+    pub fn meets_target(&self, target: &U256) -> Result<bool> {
+        let data = bincode::serialize(self)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        let mut hash = [0; 32];
+        hasher.result(&mut hash);
+        Ok(U256::from_big_endian(&hash) <= *target)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
-    timestamp: u128,
-    transactions: Vec<Transaction>,
-    prev_block_hash: String,
+    header: BlockHeader,
     hash: String,
-    height: usize,
-    nonce: i32,
+    transactions: Vec<Transaction>,
 }
 
 impl Block {
@@ -22,85 +66,131 @@ impl Block {
         &self.transactions
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+        self.header.prev_block_hash.clone()
     }
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
 
-    // newGenesisBlock
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+    pub fn get_height(&self) -> usize {
+        self.header.height
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    /// GetSize returns the serialized size of the block in bytes
+    pub fn get_size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
+    /// GetWork returns the expected number of hashes needed to mine the block
+    pub fn get_work(&self) -> Result<U256> {
+        get_work(self.header.bits)
+    }
+
+    /// NewGenesisBlock builds the hardcoded first block of a network
+    pub fn new_genesis_block(params: &ChainParams) -> Result<Block> {
+        let output = TXOutput {
+            value: params.get_block_subsidy(0),
+            pub_key_hash: params.decode_address(params.genesis_address)?,
+        };
+        let coinbase = Transaction::new_coinbase_with_output(
+            output,
+            0,
+            String::from(params.genesis_coinbase_data),
+        )?;
+        let mut block =
+            Block::new_template(vec![coinbase], String::new(), 0, params.pow_limit_bits)?;
+        block.header.timestamp = params.genesis_timestamp;
+        block.seal(params.genesis_nonce)?;
+        Ok(block)
     }
 
-    pub fn new_block(
+    /// NewTemplate creates a block that still has to be mined
+    pub fn new_template(
         data: Vec<Transaction>,
         prev_block_hash: String,
         height: usize,
+        bits: u32,
     ) -> Result<Block> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
         let mut block = Block {
-            timestamp: timestamp,
-            transactions: data,
-            prev_block_hash,
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash,
+                merkle_root: Vec::new(),
+                timestamp,
+                bits,
+                nonce: 0,
+                height,
+            },
             hash: String::new(),
-            height,
-            nonce: 0,
+            transactions: data,
         };
-        block.run_proof_if_work()?;
+        block.header.merkle_root = block.hash_transactions()?;
         Ok(block)
     }
 
-    fn run_proof_if_work(&mut self) -> Result<()> {
-        info!("Minnning the block");
-        while !self.validate()? {
-            self.nonce += 1;
-        }
+    /// CheckProofOfWork checks the proof of work of a received block
+    /// and that its stored hash matches its header
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        Ok(self.header.check_proof_of_work()? && self.header.hash()? == self.hash)
+    }
 
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
-        Ok(())
+    /// CheckMerkleRoot checks that the committed merkle root matches the transactions
+    pub fn check_merkle_root(&self) -> Result<bool> {
+        Ok(self.hash_transactions()? == self.header.merkle_root)
     }
 
-    /// HashTransactions returns a hash of the transactions in the block
-    fn hash_transactions(&mut self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &mut self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
+    /// Mine runs the proof of work of a template on every CPU
+    pub fn mine(self) -> Result<Block> {
+        match Miner::new(Miner::default_threads()).mine(self, &AtomicBool::new(false))? {
+            Some(block) => Ok(block),
+            None => Err(format_err!("Mining was cancelled")),
         }
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
-
-        Ok(tree.root())
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.transactions.clone(),
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce,
-        );
+    /// SetExtraNonce changes the extra nonce of the coinbase, which gives the
+    /// miner a new merkle root and so a new nonce space
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<()> {
+        match self.transactions.first_mut() {
+            Some(coinbase) if coinbase.is_coinbase() => coinbase.set_extra_nonce(extra_nonce)?,
+            _ => return Err(format_err!("Block template has no coinbase")),
+        }
+        self.header.merkle_root = self.hash_transactions()?;
+        Ok(())
+    }
 
-        let bytes = bincode::serialize(&content)?;
-        Ok(bytes)
+    /// SetTimestamp changes the time of a block that still has to be mined
+    pub fn set_timestamp(&mut self, timestamp: u128) {
+        self.header.timestamp = timestamp;
     }
 
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
+    /// Seal sets the nonce found by the miner and the resulting block hash
+    pub fn seal(&mut self, nonce: u32) -> Result<()> {
+        self.header.nonce = nonce;
+        self.hash = self.header.hash()?;
+        Ok(())
+    }
 
-        let mut vec1 = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
-        //println!("{:?}", vec1);
+    /// HashTransactions returns a hash of the transactions in the block
+    fn hash_transactions(&self) -> Result<Vec<u8>> {
+        let mut transactions = Vec::new();
+        for tx in &self.transactions {
+            transactions.push(tx.clone().hash()?.as_bytes().to_owned());
+        }
+        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&transactions);
 
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+        Ok(tree.root())
     }
 }
 
@@ -121,13 +211,13 @@ impl Merge for MergeTX {
 }
 #[cfg(test)]
 mod tests {
-    use crate::blockchain::Blockchain;
-
     use super::*;
+    use crate::testutil::new_chain;
 
     #[test]
     fn test_blockchain() {
-        let mut b = Blockchain::new().unwrap();
+        let (_dir, utxo) = new_chain("blockchain");
+        let b = &utxo.blockchain;
         // b.add_block("data".to_string());
         // b.add_block("data2".to_string());
         // b.add_block("data6666666666666666666666".to_string());
@@ -136,4 +226,27 @@ mod tests {
             println!("item {:?}", item)
         }
     }
+
+    #[test]
+    fn test_merkle_root() {
+        let tx = Transaction::new_coinbase(
+            String::from(params().genesis_address),
+            String::from("test"),
+            0,
+            0,
+        )
+        .unwrap();
+        let mut block = Block::new_template(vec![tx], String::new(), 0, params().pow_limit_bits)
+            .unwrap()
+            .mine()
+            .unwrap();
+        assert!(block.check_merkle_root().unwrap());
+        assert!(block.check_proof_of_work().unwrap());
+
+        assert!(block.get_header().check_proof_of_work().unwrap());
+
+        block.transactions[0].vout[0].value = 1000;
+        assert!(!block.check_merkle_root().unwrap());
+        assert!(block.check_proof_of_work().unwrap());
+    }
 }
@@ -1,19 +1,29 @@
-use std::collections::HashMap;
-
 use failure::format_err;
 use log::info;
 
-use crate::block::{self, Block};
-use crate::errors::Result;
+use crate::block::Block;
+use crate::chainparams::{params, ChainParams};
+use crate::errors::{Misbehavior, Result};
+use crate::pow::{compact_to_target, target_to_compact, U256};
+use crate::timedata::get_adjusted_time;
 use crate::transaction::Transaction;
-use crate::txs::{TXOutput, TXOutputs};
+use crate::txs::BlockUndo;
 
-const TARGET_HEXT: usize = 4;
+/// MEDIAN_TIME_SPAN is the number of blocks whose median timestamp a new block must exceed
+const MEDIAN_TIME_SPAN: usize = 11;
 
 #[derive(Debug)]
 pub struct Blockchain {
     current_hash: String,
     db: sled::Db,
+    /// chain_work maps a block hash to the total work of the chain ending at it
+    chain_work: sled::Tree,
+    /// undo maps a block hash to the outputs the block spent
+    undo: sled::Tree,
+    /// heights maps the height of every block of the main chain to its hash
+    heights: sled::Tree,
+    /// dir is the data directory the databases of the chain live in
+    dir: String,
 }
 
 pub struct BlockchainIter<'a> {
@@ -23,211 +33,532 @@ pub struct BlockchainIter<'a> {
 
 impl Blockchain {
     pub fn new() -> Result<Blockchain> {
-        info!("open blockchain");
-
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database first");
-        info!("Found block database");
-        let lasthash = String::from_utf8(hash.to_vec())?;
-        Ok(Blockchain {
-            current_hash: lasthash.clone(),
-            db,
-        })
+        Blockchain::open(&params().get_data_dir())
     }
 
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
-        info!("Creating new block chain");
+    /// Open opens the blockchain kept in the given data directory
+    pub fn open(dir: &str) -> Result<Blockchain> {
+        info!("open blockchain in {}", dir);
 
-        if let Err(e) = std::fs::remove_dir_all("data/blocks") {
-            info!("blocks not exist to delete")
+        let db = sled::open(format!("{}/blocks", dir))?;
+        let last = db.get("LAST")?;
+        if last.is_some() && db.get(params().genesis_hash)?.is_none() {
+            return Err(format_err!(
+                "Block database is not a {} network chain, its genesis is not {}",
+                params().name,
+                params().genesis_hash
+            ));
         }
-
-        let db = sled::open("data/blocks")?;
-        info!("Creating new block database");
-        const GENESIS_COINBASE_DATA: &str = "ss";
-        // hele siual altindadi
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-        let block = Block::new_genesis_block(cbtx);
-        db.insert(block.get_hash(), bincode::serialize(&block)?)?;
-        db.insert("LAST", block.get_hash().as_bytes()).unwrap();
-        let bc = Blockchain {
-            current_hash: block.get_hash(),
+        let chain_work = db.open_tree("chainwork")?;
+        let undo = db.open_tree("undo")?;
+        let heights = db.open_tree("heights")?;
+        let mut bc = Blockchain {
+            current_hash: String::new(),
             db,
+            chain_work,
+            undo,
+            heights,
+            dir: dir.to_string(),
         };
-        bc.db.flush()?;
+        match last {
+            Some(hash) => {
+                info!("Found block database");
+                bc.current_hash = String::from_utf8(hash.to_vec())?;
+                if bc.get_block_hash_by_height(0)?.as_deref() != Some(params().genesis_hash) {
+                    return Err(format_err!(
+                        "Block database main chain does not start with the {} genesis {}",
+                        params().name,
+                        params().genesis_hash
+                    ));
+                }
+            }
+            None => {
+                info!("Empty block database, starting from the genesis block");
+                let genesis = Block::new_genesis_block(params())?;
+                bc.db
+                    .insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
+                bc.chain_work
+                    .insert(genesis.get_hash(), &encode_work(genesis.get_work()?))?;
+                bc.update_tip(&genesis)?;
+            }
+        }
         Ok(bc)
     }
 
-    pub fn add_block(&mut self, data: Vec<Transaction>) -> Result<Block> {
-        let lsathash = self.db.get("LAST")?.unwrap();
+    /// CreateBlockchain starts a new block database from the genesis block of the network,
+    /// it refuses to touch an existing one
+    pub fn create_blockchain() -> Result<Blockchain> {
+        let path = params().path("blocks");
+        if std::path::Path::new(&path).exists() {
+            return Err(format_err!("Block database {} already exists", path));
+        }
+        info!("Creating new block chain");
+        Blockchain::new()
+    }
+
+    /// Path returns where the database with the given name lives next to the chain
+    pub fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.dir, name)
+    }
 
-        let new_block = Block::new_block(data, String::from_utf8(lsathash.to_vec())?, TARGET_HEXT)?;
+    /// MineBlock mines a new block with the provided transactions
+    pub fn mine_block(&mut self, data: Vec<Transaction>) -> Result<Block> {
+        info!("mine a new block");
+        let new_block = self.new_block_template(data)?.mine()?;
+        let work = self
+            .get_chain_work(&self.current_hash)?
+            .saturating_add(new_block.get_work()?);
         self.db
             .insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
-        self.current_hash = new_block.get_hash();
+        self.chain_work
+            .insert(new_block.get_hash(), &encode_work(work))?;
+        self.update_tip(&new_block)?;
         Ok(new_block)
     }
 
-    /// FindUnspentTransactions returns a list of transactions containing inspent outputs
-    fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
-        let mut spent_txos: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut unspend_txs: Vec<Transaction> = Vec::new();
+    /// NewBlockTemplate creates the next block on the tip, ready to be mined
+    pub fn new_block_template(&self, data: Vec<Transaction>) -> Result<Block> {
+        if self.current_hash.is_empty() {
+            return Err(format_err!("Must create a new block database first"));
+        }
+        let height = self.get_best_height()? + 1;
+        let bits = self.get_next_bits(&self.current_hash)?;
+        let mut block =
+            Block::new_template(data, self.current_hash.clone(), height as usize, bits)?;
+        let min_time = self.get_median_time_past(&self.current_hash)? + 1;
+        block.set_timestamp(get_adjusted_time().max(min_time));
+        let size = block.get_size()?;
+        if size > params().max_block_size {
+            return Err(format_err!("Block template is too large: {} bytes", size));
+        }
+        Ok(block)
+    }
 
-        for block in self.iter() {
-            for tx in block.get_transaction() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spent_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
+    /// AddBlock saves a block received from a peer and makes it the tip
+    /// when its branch has more work than the current one
+    pub fn add_block(&mut self, block: Block) -> Result<()> {
+        if self.db.get(block.get_hash())?.is_some() {
+            return Ok(());
+        }
+        let mut work = block.get_work()?;
+        if !block.get_prev_hash().is_empty() {
+            work = work.saturating_add(self.get_chain_work(&block.get_prev_hash())?);
+        }
+        self.db
+            .insert(block.get_hash(), bincode::serialize(&block)?)?;
+        self.chain_work
+            .insert(block.get_hash(), &encode_work(work))?;
 
-                    if tx.vout[index].is_locked_with_key(address) {
-                        unspend_txs.push(tx.to_owned())
-                    }
-                }
+        if self.current_hash.is_empty() || work > self.get_chain_work(&self.current_hash)? {
+            self.update_tip(&block)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
 
-                if !tx.is_coinbase() {
-                    for i in &tx.vin {
-                        if i.can_unlock_output_with(address) {
-                            match spent_txos.get_mut(&i.txid) {
-                                Some(v) => {
-                                    v.push(i.vout);
-                                }
-                                None => {
-                                    spent_txos.insert(i.txid.clone(), vec![i.vout]);
-                                }
-                            }
-                        }
-                    }
-                }
+    /// SetTip makes a stored block the tip of the main chain
+    pub fn set_tip(&mut self, hash: &str) -> Result<()> {
+        let block = self.get_block(hash)?;
+        self.update_tip(&block)
+    }
+
+    /// UpdateTip saves the new tip and moves the height index to its branch
+    fn update_tip(&mut self, tip: &Block) -> Result<()> {
+        self.index_main_chain(tip)?;
+        self.db.insert("LAST", tip.get_hash().as_bytes())?;
+        self.db.flush()?;
+        self.current_hash = tip.get_hash();
+        Ok(())
+    }
+
+    /// IndexMainChain makes the height index end at the given block: the heights
+    /// above it are dropped and its ancestors replace the blocks of another branch
+    fn index_main_chain(&self, tip: &Block) -> Result<()> {
+        let tip_height = tip.get_height() as u64;
+        while let Some((key, _)) = self.heights.last()? {
+            if decode_height(&key)? <= tip_height {
+                break;
+            }
+            self.heights.remove(key)?;
+        }
+        let mut block = tip.clone();
+        loop {
+            let key = (block.get_height() as u64).to_be_bytes();
+            if self.heights.get(key)?.as_deref() == Some(block.get_hash().as_bytes()) {
+                break;
             }
+            self.heights.insert(key, block.get_hash().as_bytes())?;
+            if block.get_prev_hash().is_empty() {
+                break;
+            }
+            block = self.get_block(&block.get_prev_hash())?;
         }
+        self.heights.flush()?;
+        Ok(())
+    }
+
+    /// CheckBlock checks a received block on its own and against its parent,
+    /// which may be on a side branch. A broken rule is returned as a Misbehavior,
+    /// other errors come from the databases
+    pub fn check_block(&self, block: &Block) -> Result<()> {
+        self.check_block_with(params(), block)
+    }
 
-        unspend_txs
+    /// CheckBlockWith checks a block against the rules of the given network
+    fn check_block_with(&self, params: &ChainParams, block: &Block) -> Result<()> {
+        if !block.check_proof_of_work()? {
+            return Err(Misbehavior::error(
+                100,
+                format!("Block {} has an invalid proof of work", block.get_hash()),
+            ));
+        }
+        if !block.check_merkle_root()? {
+            return Err(Misbehavior::error(
+                100,
+                format!("Block {} has an invalid merkle root", block.get_hash()),
+            ));
+        }
+        let size = block.get_size()?;
+        if size > params.max_block_size {
+            return Err(Misbehavior::error(
+                100,
+                format!("Block {} is too large: {} bytes", block.get_hash(), size),
+            ));
+        }
+        for tx in block.get_transaction() {
+            let tx_size = tx.get_size()?;
+            if tx_size > params.max_tx_size {
+                return Err(Misbehavior::error(
+                    100,
+                    format!(
+                        "Block {} has tx {} too large: {} bytes",
+                        block.get_hash(),
+                        tx.id,
+                        tx_size
+                    ),
+                ));
+            }
+        }
+        // the block may become valid later, or our clock is wrong: it is not scored
+        if block.get_timestamp() > get_adjusted_time() + params.max_future_block_time {
+            return Err(format_err!(
+                "Block {} has a timestamp too far in the future",
+                block.get_hash()
+            ));
+        }
+        if block.get_prev_hash().is_empty() {
+            if block.get_hash() != params.genesis_hash {
+                return Err(Misbehavior::error(
+                    100,
+                    format!("Block {} is an unexpected genesis block", block.get_hash()),
+                ));
+            }
+            return Ok(());
+        }
+        let parent = self.get_block(&block.get_prev_hash())?;
+        let bits = self.get_next_bits_with(params, &parent.get_hash())?;
+        if block.get_header().bits != bits {
+            return Err(Misbehavior::error(
+                100,
+                format!(
+                    "Block {} has target bits {:#010x} instead of {:#010x}",
+                    block.get_hash(),
+                    block.get_header().bits,
+                    bits
+                ),
+            ));
+        }
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(Misbehavior::error(
+                100,
+                format!(
+                    "Block {} has a wrong height {}",
+                    block.get_hash(),
+                    block.get_height()
+                ),
+            ));
+        }
+        let median_time = self.get_median_time_past(&parent.get_hash())?;
+        if block.get_timestamp() <= median_time {
+            return Err(Misbehavior::error(
+                100,
+                format!(
+                    "Block {} has a timestamp not after the median time {} of the previous blocks",
+                    block.get_hash(),
+                    median_time
+                ),
+            ));
+        }
+        Ok(())
     }
 
-    /// FindUTXO finds and returns all unspent transaction outputs
-    pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
+    /// GetMedianTimePast returns the median timestamp of the given block and of
+    /// its ancestors, MEDIAN_TIME_SPAN blocks at most
+    pub fn get_median_time_past(&self, hash: &str) -> Result<u128> {
+        let mut timestamps = Vec::new();
+        let mut current = hash.to_string();
+        while timestamps.len() < MEDIAN_TIME_SPAN && !current.is_empty() {
+            let block = self.get_block(&current)?;
+            timestamps.push(block.get_timestamp());
+            current = block.get_prev_hash();
+        }
+        timestamps.sort();
+        Ok(timestamps[timestamps.len() / 2])
+    }
 
-        for block in self.iter() {
-            for tx in block.get_transaction() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
+    /// GetNextBits returns the target bits of the block following the given parent.
+    /// The target changes every retarget_interval blocks, depending on how long the
+    /// last interval took compared to the target_spacing of the network
+    pub fn get_next_bits(&self, prev_hash: &str) -> Result<u32> {
+        self.get_next_bits_with(params(), prev_hash)
+    }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
-                    }
-                }
+    fn get_next_bits_with(&self, params: &ChainParams, prev_hash: &str) -> Result<u32> {
+        let parent = self.get_block(prev_hash)?;
+        let bits = parent.get_header().bits;
+        if params.pow_no_retargeting || (parent.get_height() + 1) % params.retarget_interval != 0 {
+            return Ok(bits);
+        }
 
-                if !tx.is_coinbase() {
-                    for i in &tx.vin {
-                        match spend_txos.get_mut(&i.txid) {
-                            Some(v) => {
-                                v.push(i.vout);
-                            }
-                            None => {
-                                spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                            }
-                        }
-                    }
-                }
-            }
+        let mut first = parent.clone();
+        for _ in 1..params.retarget_interval {
+            first = self.get_block(&first.get_prev_hash())?;
         }
+        let timespan = parent.get_timestamp().saturating_sub(first.get_timestamp());
+        let next = retarget(params, bits, timespan)?;
+        info!(
+            "retarget at height {}: {:#010x} -> {:#010x} after {} ms",
+            parent.get_height() + 1,
+            bits,
+            next,
+            timespan
+        );
+        Ok(next)
+    }
 
-        utxos
+    /// RemoveBlock forgets a stored block that turned out to be invalid
+    pub fn remove_block(&self, block_hash: &str) -> Result<()> {
+        self.db.remove(block_hash)?;
+        self.chain_work.remove(block_hash)?;
+        self.undo.remove(block_hash)?;
+        self.db.flush()?;
+        Ok(())
     }
 
-    /// FindUnspentTransactions returns a list of transactions containing unspent outputs
-    pub fn find_spendable_outputs(
-        &self,
-        address: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated: i32 = 0;
-        let unspent_txs = self.find_unspent_transactions(address);
+    /// GetChainWork returns the total work of the chain ending at the given block
+    pub fn get_chain_work(&self, hash: &str) -> Result<U256> {
+        match self.chain_work.get(hash)? {
+            Some(work) => Ok(U256::from_big_endian(&work)),
+            None => Err(format_err!("No chain work for block {}", hash)),
+        }
+    }
 
-        for tx in unspent_txs {
-            for index in 0..tx.vout.len() {
-                if tx.vout[index].is_locked_with_key(address) && accumulated < amount {
-                    match unspent_outputs.get_mut(&tx.id) {
-                        Some(v) => v.push(index as i32),
-                        None => {
-                            unspent_outputs.insert(tx.id.clone(), vec![index as i32]);
-                        }
-                    }
-                    accumulated += tx.vout[index].value;
+    /// PutUndo saves the undo data of a block
+    pub fn put_undo(&self, block_hash: &str, undo: &BlockUndo) -> Result<()> {
+        self.undo.insert(block_hash, bincode::serialize(undo)?)?;
+        self.undo.flush()?;
+        Ok(())
+    }
 
-                    if accumulated >= amount {
-                        return Ok((accumulated, unspent_outputs));
-                    }
-                }
-            }
+    /// GetUndo returns the undo data of a block, None if it was never connected
+    pub fn get_undo(&self, block_hash: &str) -> Result<Option<BlockUndo>> {
+        match self.undo.get(block_hash)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
         }
-        Ok((accumulated, unspent_outputs))
     }
 
-    pub fn iter(&self) -> BlockchainIter {
-        BlockchainIter {
-            current_hash: self.current_hash.clone(),
-            bc: &self,
+    /// GetReorgPath returns the blocks to disconnect, tip first, and the blocks
+    /// to connect, oldest first, to move the tip from one block to another
+    pub fn get_reorg_path(&self, from: &str, to: &str) -> Result<(Vec<Block>, Vec<Block>)> {
+        let mut old = self.get_block(from)?;
+        let mut new = self.get_block(to)?;
+        let mut disconnect = Vec::new();
+        let mut connect = Vec::new();
+        while old.get_height() > new.get_height() {
+            let prev = old.get_prev_hash();
+            disconnect.push(old);
+            old = self.get_block(&prev)?;
         }
+        while new.get_height() > old.get_height() {
+            let prev = new.get_prev_hash();
+            connect.push(new);
+            new = self.get_block(&prev)?;
+        }
+        while old.get_hash() != new.get_hash() {
+            let (old_prev, new_prev) = (old.get_prev_hash(), new.get_prev_hash());
+            disconnect.push(old);
+            connect.push(new);
+            old = self.get_block(&old_prev)?;
+            new = self.get_block(&new_prev)?;
+        }
+        connect.reverse();
+        Ok((disconnect, connect))
     }
 
-    /// FindTransaction finds a transaction by its ID
-    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
-        for b in self.iter() {
-            for tx in b.get_transaction() {
-                if tx.id == id {
-                    return Ok(tx.clone());
-                }
+    /// GetTipHash returns the hash of the latest block, empty before the genesis
+    pub fn get_tip_hash(&self) -> String {
+        self.current_hash.clone()
+    }
+
+    /// GetBestHeight returns the height of the latest block, -1 before the genesis
+    pub fn get_best_height(&self) -> Result<i32> {
+        match self.heights.last()? {
+            Some((key, _)) => Ok(decode_height(&key)? as i32),
+            None => Ok(-1),
+        }
+    }
+
+    /// GetBlockHashByHeight returns the hash of the main chain block at the given height
+    pub fn get_block_hash_by_height(&self, height: usize) -> Result<Option<String>> {
+        match self.heights.get((height as u64).to_be_bytes())? {
+            Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// GetBlockByHeight returns the main chain block at the given height
+    pub fn get_block_by_height(&self, height: usize) -> Result<Block> {
+        match self.get_block_hash_by_height(height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Err(format_err!("No block at height {}", height)),
+        }
+    }
+
+    /// GetBlock finds a block by its hash and returns it
+    pub fn get_block(&self, block_hash: &str) -> Result<Block> {
+        let data = match self.db.get(block_hash)? {
+            Some(d) => d,
+            None => return Err(format_err!("Block {} is not found", block_hash)),
+        };
+        let block = bincode::deserialize(&data)?;
+        Ok(block)
+    }
+
+    /// GetBlockHashs returns the hashes of the blocks of the main chain, tip first
+    pub fn get_block_hashes(&self) -> Result<Vec<String>> {
+        let mut list = Vec::new();
+        for kv in self.heights.iter().rev() {
+            let (_, hash) = kv?;
+            list.push(String::from_utf8(hash.to_vec())?);
+        }
+        Ok(list)
+    }
+
+    /// GetBlockLocator returns hashes from the tip back to the genesis block,
+    /// dense near the tip and with exponentially growing gaps after it
+    pub fn get_block_locator(&self) -> Result<Vec<String>> {
+        let mut locator = Vec::new();
+        let mut height = self.get_best_height()? as i64;
+        let mut step = 1;
+        while height > 0 {
+            if let Some(hash) = self.get_block_hash_by_height(height as usize)? {
+                locator.push(hash);
             }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+        if let Some(genesis) = self.get_block_hash_by_height(0)? {
+            locator.push(genesis);
         }
-        Err(format_err!("Transaction is not found"))
+        Ok(locator)
     }
 
-    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
-        let mut prev_txs = HashMap::new();
-        for vin in &tx.vin {
-            let prev_tx = self.find_transaction(&vin.txid)?;
-            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+    /// GetBlockHashesAfter returns, oldest first, the hashes of the main chain blocks
+    /// that follow the most recent locator entry found in it
+    pub fn get_block_hashes_after(&self, locator: &[String], limit: usize) -> Result<Vec<String>> {
+        let mut start = 0;
+        for hash in locator {
+            if let Ok(block) = self.get_block(hash) {
+                if self.get_block_hash_by_height(block.get_height())?.as_ref() == Some(hash) {
+                    start = block.get_height() + 1;
+                    break;
+                }
+            }
+        }
+        let mut list = Vec::new();
+        for kv in self
+            .heights
+            .range((start as u64).to_be_bytes()..)
+            .take(limit)
+        {
+            let (_, hash) = kv?;
+            list.push(String::from_utf8(hash.to_vec())?);
         }
-        Ok(prev_txs)
+        Ok(list)
     }
 
-    ///SignTransaction signs input of a Transaction
-    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
-        let prev_txs = self.get_prev_txs(tx)?;
-        tx.sign(private_key, prev_txs)?;
-        Ok(())
+    pub fn iter(&self) -> BlockchainIter<'_> {
+        BlockchainIter {
+            current_hash: self.current_hash.clone(),
+            bc: self,
+        }
     }
 
-    pub fn verify_transaction(&self, tx: &mut Transaction) -> Result<bool> {
-        let prev_txs = self.get_prev_txs(tx)?;
-        tx.verify(prev_txs)
+    /// GetIssuedSupply returns the coins created by the coinbases of the main chain:
+    /// what each coinbase claims beyond the fees of its block, at most the block subsidy
+    pub fn get_issued_supply(&self) -> Result<i64> {
+        let mut supply = 0;
+        for block in self.iter() {
+            let txs = block.get_transaction();
+            let coinbase = match txs.first() {
+                Some(tx) if tx.is_coinbase() => tx,
+                _ => continue,
+            };
+            let reward: i64 = coinbase.vout.iter().map(|out| out.value as i64).sum();
+            // the undo data holds the outputs the block spent, a block with only
+            // its coinbase spends nothing
+            let spent: i64 = if txs.len() > 1 {
+                match self.get_undo(&block.get_hash())? {
+                    Some(undo) => undo.spent.iter().map(|s| s.output.value as i64).sum(),
+                    None => return Err(format_err!("No undo data for block {}", block.get_hash())),
+                }
+            } else {
+                0
+            };
+            let paid: i64 = txs[1..]
+                .iter()
+                .flat_map(|tx| tx.vout.iter())
+                .map(|out| out.value as i64)
+                .sum();
+            let fees = (spent - paid).max(0);
+            let subsidy = params().get_block_subsidy(block.get_height()) as i64;
+            supply += (reward - fees).clamp(0, subsidy);
+        }
+        Ok(supply)
     }
 }
 
+/// DecodeHeight reads a key of the height index
+fn decode_height(key: &[u8]) -> Result<u64> {
+    Ok(u64::from_be_bytes(<[u8; 8]>::try_from(key)?))
+}
+
+/// EncodeWork returns the 32 big endian bytes a chain work is stored as
+fn encode_work(work: U256) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    work.to_big_endian(&mut bytes);
+    bytes
+}
+
+/// Retarget scales the target by the time the last interval took compared to
+/// the wanted time, by at most a factor 4 and up to the proof of work limit
+fn retarget(params: &ChainParams, bits: u32, timespan: u128) -> Result<u32> {
+    let expected = params.target_spacing * (params.retarget_interval as u128 - 1);
+    let timespan = timespan.clamp(expected / 4, expected * 4);
+    let target = compact_to_target(bits)?;
+    let limit = compact_to_target(params.pow_limit_bits)?;
+    let next = match target.checked_mul(U256::from(timespan)) {
+        Some(product) => product / U256::from(expected),
+        // only targets close to the limit overflow, dividing first is precise enough there
+        // and a target above the 256 bits is capped by the limit anyway
+        None => (target / U256::from(expected)).saturating_mul(U256::from(timespan)),
+    };
+    Ok(target_to_compact(next.min(limit)))
+}
+
 impl<'a> Iterator for BlockchainIter<'a> {
     type Item = Block;
     fn next(&mut self) -> Option<Self::Item> {
@@ -247,3 +578,278 @@ impl<'a> Iterator for BlockchainIter<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chainparams::{MAIN_PARAMS, REGTEST_PARAMS};
+    use crate::ed25519::Wallet;
+    use crate::testutil::{
+        funded_chain, mine, mine_on, mine_with_bits, misbehavior_score, new_chain, spendable,
+    };
+    use crate::txs::TXOutput;
+
+    #[test]
+    fn test_retarget() {
+        let params = &MAIN_PARAMS;
+        let expected = params.target_spacing * (params.retarget_interval as u128 - 1);
+        let bits = 0x1e00ffff;
+        let target = compact_to_target(bits).unwrap();
+        assert_eq!(retarget(params, bits, expected).unwrap(), bits);
+        assert_eq!(
+            compact_to_target(retarget(params, bits, expected / 2).unwrap()).unwrap(),
+            target / 2
+        );
+        assert_eq!(
+            compact_to_target(retarget(params, bits, 0).unwrap()).unwrap(),
+            target / 4
+        );
+        assert_eq!(
+            compact_to_target(retarget(params, bits, expected * 2).unwrap()).unwrap(),
+            target * 2
+        );
+        assert_eq!(
+            retarget(params, params.pow_limit_bits, expected * 10).unwrap(),
+            params.pow_limit_bits
+        );
+        // four times a limit close to the 256 bits overflows
+        let limit = REGTEST_PARAMS.pow_limit_bits;
+        assert_eq!(
+            retarget(&REGTEST_PARAMS, limit, expected * 10).unwrap(),
+            limit
+        );
+    }
+
+    /// Filler returns a coinbase carrying data, it makes a transaction of about size bytes
+    fn filler(size: usize) -> Transaction {
+        let address = Wallet::new().get_address();
+        Transaction::new_coinbase(address, "x".repeat(size), 1, 0).unwrap()
+    }
+
+    #[test]
+    fn test_check_block_size() {
+        let (_dir, utxo) = new_chain("block-size");
+        let bc = &utxo.blockchain;
+        let tip = bc.get_tip_hash();
+        let miner = Wallet::new().get_address();
+        let max_tx_size = params().max_tx_size;
+
+        let block = mine_on(bc, &tip, &miner, vec![filler(max_tx_size - 1000)]);
+        bc.check_block(&block).unwrap();
+
+        let block = mine_on(bc, &tip, &miner, vec![filler(max_tx_size)]);
+        let e = bc.check_block(&block).unwrap_err();
+        assert!(e.to_string().contains("has tx"));
+
+        // every transaction fits, all of them do not
+        let count = params().max_block_size / (max_tx_size - 1000) + 1;
+        let txs = (0..count).map(|_| filler(max_tx_size - 1000)).collect();
+        let block = mine_on(bc, &tip, &miner, txs);
+        let e = bc.check_block(&block).unwrap_err();
+        assert!(e.to_string().contains("is too large"));
+    }
+
+    /// BlockAt mines a block on the tip with the given timestamp
+    fn block_at(bc: &Blockchain, timestamp: u128) -> Block {
+        let tip = bc.get_tip_hash();
+        let height = bc.get_best_height().unwrap() as usize + 1;
+        let address = Wallet::new().get_address();
+        let coinbase = Transaction::new_coinbase(address, String::new(), height, 0).unwrap();
+        let bits = bc.get_next_bits(&tip).unwrap();
+        let mut block = Block::new_template(vec![coinbase], tip, height, bits).unwrap();
+        block.set_timestamp(timestamp);
+        block.mine().unwrap()
+    }
+
+    #[test]
+    fn test_check_block_timestamp() {
+        let (_dir, mut utxo) = new_chain("block-timestamp");
+        let miner = Wallet::new().get_address();
+        for _ in 0..3 {
+            mine(&mut utxo, &miner, Vec::new());
+        }
+        let bc = &utxo.blockchain;
+        let median_time = bc.get_median_time_past(&bc.get_tip_hash()).unwrap();
+
+        let e = bc.check_block(&block_at(bc, median_time)).unwrap_err();
+        assert!(e.to_string().contains("not after the median time"));
+        assert_eq!(misbehavior_score::<()>(Err(e)), Some(100));
+        bc.check_block(&block_at(bc, median_time + 1)).unwrap();
+
+        // the clock of the node may be late, a block from the future is not scored
+        let max_time = get_adjusted_time() + params().max_future_block_time;
+        let e = bc
+            .check_block(&block_at(bc, max_time + 60_000))
+            .unwrap_err();
+        assert!(e.to_string().contains("too far in the future"));
+        assert_eq!(misbehavior_score::<()>(Err(e)), None);
+        bc.check_block(&block_at(bc, max_time - 60_000)).unwrap();
+    }
+
+    #[test]
+    fn test_check_block_bits() {
+        let (_dir, mut utxo) = new_chain("block-bits");
+        let miner = Wallet::new().get_address();
+        // the first interval starts at the genesis block, long ago, so its target stays at the limit
+        for _ in 0..2 * REGTEST_PARAMS.retarget_interval - 1 {
+            mine(&mut utxo, &miner, Vec::new());
+        }
+        // the blocks of the second one came much faster than the target spacing, the target goes down
+        let retargeting = ChainParams {
+            pow_no_retargeting: false,
+            ..REGTEST_PARAMS
+        };
+        let easy_bits = REGTEST_PARAMS.pow_limit_bits;
+        let tip = utxo.blockchain.get_tip_hash();
+        let next_bits = utxo
+            .blockchain
+            .get_next_bits_with(&retargeting, &tip)
+            .unwrap();
+        assert_eq!(next_bits, retarget(&retargeting, easy_bits, 0).unwrap());
+        assert_ne!(next_bits, easy_bits);
+
+        let block_with_bits = |bc: &Blockchain, bits: u32| {
+            let tip = bc.get_tip_hash();
+            let height = bc.get_best_height().unwrap() as usize + 1;
+            let coinbase =
+                Transaction::new_coinbase(miner.clone(), String::new(), height, 0).unwrap();
+            mine_with_bits(bc, &tip, vec![coinbase], bits)
+        };
+
+        // at a retarget height the old target is wrong
+        let bc = &utxo.blockchain;
+        let block = block_with_bits(bc, easy_bits);
+        bc.check_block(&block).unwrap();
+        let e = bc.check_block_with(&retargeting, &block).unwrap_err();
+        assert!(e.to_string().contains("has target bits"));
+        assert_eq!(misbehavior_score::<()>(Err(e)), Some(100));
+        let block = block_with_bits(bc, next_bits);
+        bc.check_block_with(&retargeting, &block).unwrap();
+        utxo.blockchain.add_block(block).unwrap();
+
+        // between retarget heights the target of the parent is kept
+        let bc = &utxo.blockchain;
+        let block = block_with_bits(bc, easy_bits);
+        let e = bc.check_block_with(&retargeting, &block).unwrap_err();
+        assert!(e.to_string().contains("has target bits"));
+        assert_eq!(misbehavior_score::<()>(Err(e)), Some(100));
+        let block = block_with_bits(bc, next_bits);
+        bc.check_block_with(&retargeting, &block).unwrap();
+    }
+
+    #[test]
+    fn test_height_index_after_reorganization() {
+        let (_dir, mut utxo) = new_chain("height-index");
+        let miner = Wallet::new().get_address();
+        let fork = mine(&mut utxo, &miner, Vec::new());
+        for _ in 0..4 {
+            mine(&mut utxo, &miner, Vec::new());
+        }
+
+        // two blocks, the last one with a much harder target, outweigh the four of the tip
+        let b2 = mine_on(&utxo.blockchain, &fork.get_hash(), &miner, Vec::new());
+        utxo.blockchain.add_block(b2.clone()).unwrap();
+        let coinbase = Transaction::new_coinbase(miner, String::new(), 3, 0).unwrap();
+        let b3 = mine_with_bits(&utxo.blockchain, &b2.get_hash(), vec![coinbase], 0x2000ffff);
+        utxo.blockchain.add_block(b3.clone()).unwrap();
+
+        let bc = &utxo.blockchain;
+        assert_eq!(bc.get_tip_hash(), b3.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 3);
+        assert_eq!(
+            bc.get_block_hash_by_height(0).unwrap(),
+            Some(params().genesis_hash.to_string())
+        );
+        assert_eq!(
+            bc.get_block_hash_by_height(1).unwrap(),
+            Some(fork.get_hash())
+        );
+        assert_eq!(bc.get_block_hash_by_height(2).unwrap(), Some(b2.get_hash()));
+        assert_eq!(bc.get_block_hash_by_height(3).unwrap(), Some(b3.get_hash()));
+        assert_eq!(bc.get_block_hash_by_height(4).unwrap(), None);
+        assert_eq!(bc.get_block_hash_by_height(5).unwrap(), None);
+    }
+
+    #[test]
+    fn test_issued_supply() {
+        let (_dir, mut utxo, wallet) = funded_chain("issued-supply", 1);
+        let miner = Wallet::new().get_address();
+
+        // the fees the coinbase claims were created by earlier coinbases
+        let coin = spendable(&utxo, &wallet).remove(0);
+        let tx = Transaction::new_utxo(&wallet, &miner, 30, 5, vec![coin]).unwrap();
+        let tip = utxo.blockchain.get_tip_hash();
+        let height = utxo.blockchain.get_best_height().unwrap() as usize + 1;
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new(), height, 5).unwrap();
+        let bits = utxo.blockchain.get_next_bits(&tip).unwrap();
+        let block = mine_with_bits(&utxo.blockchain, &tip, vec![coinbase, tx], bits);
+        utxo.validate_block(&block).unwrap();
+        utxo.blockchain.add_block(block.clone()).unwrap();
+        utxo.update(&block).unwrap();
+        assert_eq!(
+            utxo.blockchain.get_issued_supply().unwrap(),
+            params().get_supply_at(height + 1)
+        );
+
+        // once the max supply is issued a coinbase may only claim the fees
+        while params().get_supply_at(utxo.blockchain.get_best_height().unwrap() as usize + 1)
+            < params().max_supply
+        {
+            mine(&mut utxo, &miner, Vec::new());
+        }
+        assert_eq!(
+            utxo.blockchain.get_issued_supply().unwrap(),
+            params().max_supply
+        );
+        let tip = utxo.blockchain.get_tip_hash();
+        let height = utxo.blockchain.get_best_height().unwrap() as usize + 1;
+        assert_eq!(params().get_block_subsidy(height), 0);
+        let output = TXOutput::new(1, miner).unwrap();
+        let coinbase =
+            Transaction::new_coinbase_with_output(output, height, String::new()).unwrap();
+        let block = mine_with_bits(&utxo.blockchain, &tip, vec![coinbase], bits);
+        let e = utxo.validate_block(&block).unwrap_err();
+        assert!(e.to_string().contains("coinbase paying"));
+    }
+
+    #[test]
+    fn test_block_locator() {
+        let (_dir, mut utxo) = new_chain("locator");
+        let miner = Wallet::new().get_address();
+        for _ in 0..40 {
+            mine(&mut utxo, &miner, Vec::new());
+        }
+        let bc = &utxo.blockchain;
+        let hash_at = |height: usize| bc.get_block_hash_by_height(height).unwrap().unwrap();
+
+        // ten blocks from the tip, then gaps doubling down to the genesis block
+        let heights: Vec<usize> = (31..=40).rev().chain([29, 25, 17, 1, 0]).collect();
+        let expected: Vec<String> = heights.into_iter().map(hash_at).collect();
+        assert_eq!(bc.get_block_locator().unwrap(), expected);
+
+        // a peer that went on a side branch at height 20 gets the main chain after it
+        let (_peer_dir, mut peer) = new_chain("locator-peer");
+        for height in 1..=20 {
+            let block = bc.get_block(&hash_at(height)).unwrap();
+            peer.blockchain.add_block(block).unwrap();
+        }
+        for _ in 0..3 {
+            let tip = peer.blockchain.get_tip_hash();
+            let block = mine_on(&peer.blockchain, &tip, &miner, Vec::new());
+            peer.blockchain.add_block(block).unwrap();
+        }
+        assert_eq!(peer.blockchain.get_best_height().unwrap(), 23);
+        let locator = peer.blockchain.get_block_locator().unwrap();
+        let expected: Vec<String> = (21..=40).map(hash_at).collect();
+        assert_eq!(bc.get_block_hashes_after(&locator, 500).unwrap(), expected);
+
+        // at most a batch of hashes is sent at once
+        let expected: Vec<String> = (21..=25).map(hash_at).collect();
+        assert_eq!(bc.get_block_hashes_after(&locator, 5).unwrap(), expected);
+        let tip_locator = bc.get_block_locator().unwrap();
+        assert!(bc
+            .get_block_hashes_after(&tip_locator, 5)
+            .unwrap()
+            .is_empty());
+    }
+}
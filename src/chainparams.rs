@@ -0,0 +1,318 @@
+use std::sync::OnceLock;
+
+use bitcoincash_addr::{Address, Network as AddressNetwork};
+use failure::format_err;
+use log::info;
+
+use crate::errors::Result;
+
+/// Network names the chains a node can run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Test,
+    Regtest,
+}
+
+/// ChainParams holds everything that differs from one network to another
+#[derive(Debug)]
+pub struct ChainParams {
+    pub network: Network,
+    pub name: &'static str,
+    /// data_subdir keeps the databases of every network apart inside the data directory
+    pub data_subdir: &'static str,
+    /// magic starts every message so that nodes of other networks are rejected early
+    pub magic: [u8; 4],
+    pub default_port: &'static str,
+    /// seeds are dialed when no seed node is given on the command line,
+    /// no network has public nodes yet so they are all started with --seed
+    pub seeds: &'static [&'static str],
+    /// address_network selects the version byte of the addresses
+    pub address_network: AddressNetwork,
+    /// genesis_address receives the reward of the genesis block
+    pub genesis_address: &'static str,
+    pub genesis_coinbase_data: &'static str,
+    /// genesis_timestamp and genesis_nonce are fixed so that every node builds the same genesis
+    pub genesis_timestamp: u128,
+    pub genesis_nonce: u32,
+    pub genesis_hash: &'static str,
+    /// subsidy is the reward of a coinbase until the first halving
+    pub subsidy: i32,
+    /// subsidy_halving_interval is the number of blocks between two halvings of the subsidy
+    pub subsidy_halving_interval: usize,
+    /// max_supply caps the coins all coinbases together may ever create
+    pub max_supply: i64,
+    /// coinbase_maturity is the number of blocks to wait before the outputs of a coinbase
+    /// can be spent, so that a reorg cannot make the spending transactions invalid
+    pub coinbase_maturity: usize,
+    /// pow_limit_bits is the easiest target a block may have, the genesis uses it
+    pub pow_limit_bits: u32,
+    /// retarget_interval is the number of blocks between two target changes
+    pub retarget_interval: usize,
+    /// target_spacing is the wanted time between two blocks, in milliseconds
+    pub target_spacing: u128,
+    /// pow_no_retargeting keeps the target of the genesis forever
+    pub pow_no_retargeting: bool,
+    /// max_future_block_time is how far ahead of the network-adjusted time, in milliseconds,
+    /// a block timestamp may be
+    pub max_future_block_time: u128,
+    /// max_block_size bounds the serialized size of a block, in bytes
+    pub max_block_size: usize,
+    /// max_tx_size bounds the serialized size of a transaction, in bytes
+    pub max_tx_size: usize,
+}
+
+pub const MAIN_PARAMS: ChainParams = ChainParams {
+    network: Network::Main,
+    name: "main",
+    data_subdir: "",
+    magic: [0x73, 0x61, 0x64, 0x61],
+    default_port: "3000",
+    seeds: &[],
+    address_network: AddressNetwork::Main,
+    genesis_address: "3NDb6QpAAxooUZc2yEEkCaL3FD3PtKq3t2",
+    genesis_coinbase_data: "ss",
+    genesis_timestamp: 1792322635739,
+    genesis_nonce: 17484,
+    genesis_hash: "00003f452304f506c6d73e1c06a715c941394fd87d058f7629f027a6a45b3618",
+    subsidy: 100,
+    subsidy_halving_interval: 1000,
+    max_supply: 190_000,
+    coinbase_maturity: 10,
+    // a hash with 4 leading zero hex digits
+    pow_limit_bits: 0x1f00ffff,
+    retarget_interval: 10,
+    target_spacing: 10 * 1000,
+    pow_no_retargeting: false,
+    max_future_block_time: 2 * 60 * 60 * 1000,
+    max_block_size: 1_000_000,
+    max_tx_size: 100_000,
+};
+
+pub const TEST_PARAMS: ChainParams = ChainParams {
+    network: Network::Test,
+    name: "test",
+    data_subdir: "testnet",
+    magic: [0x74, 0x65, 0x73, 0x74],
+    default_port: "13000",
+    seeds: &[],
+    address_network: AddressNetwork::Test,
+    genesis_address: "2NDmoA9kBnRK9gMEaeMrcpXKJTZFZgSmM9y",
+    genesis_coinbase_data: "testnet",
+    genesis_timestamp: 1792322635739,
+    genesis_nonce: 47624,
+    genesis_hash: "0000ce0d9d3816768f5b905b946fbba7ba2478d077c9bcc9fe1f4f015025876b",
+    subsidy: 100,
+    subsidy_halving_interval: 1000,
+    max_supply: 190_000,
+    coinbase_maturity: 10,
+    pow_limit_bits: 0x1f00ffff,
+    retarget_interval: 10,
+    target_spacing: 10 * 1000,
+    pow_no_retargeting: false,
+    max_future_block_time: 2 * 60 * 60 * 1000,
+    max_block_size: 1_000_000,
+    max_tx_size: 100_000,
+};
+
+/// REGTEST_PARAMS is a local network where every other hash is a valid block
+pub const REGTEST_PARAMS: ChainParams = ChainParams {
+    network: Network::Regtest,
+    name: "regtest",
+    data_subdir: "regtest",
+    magic: [0x72, 0x65, 0x67, 0x74],
+    default_port: "23000",
+    seeds: &[],
+    // base58 addresses of regtest are the testnet ones
+    address_network: AddressNetwork::Test,
+    genesis_address: "2NDmoA9kBnRK9gMEaeMrcpXKJTZFZgSmM9y",
+    genesis_coinbase_data: "regtest",
+    genesis_timestamp: 1792322635739,
+    genesis_nonce: 0,
+    genesis_hash: "75d5cdf28499ecccb735d4354bf858010a877cb1cb5a8214cf70b1478e6ab898",
+    subsidy: 100,
+    subsidy_halving_interval: 150,
+    max_supply: 28_500,
+    coinbase_maturity: 10,
+    pow_limit_bits: 0x207fffff,
+    retarget_interval: 10,
+    target_spacing: 10 * 1000,
+    pow_no_retargeting: true,
+    max_future_block_time: 2 * 60 * 60 * 1000,
+    max_block_size: 1_000_000,
+    max_tx_size: 100_000,
+};
+
+static PARAMS: OnceLock<&'static ChainParams> = OnceLock::new();
+
+/// DEFAULT_DATA_DIR holds the databases unless another directory is selected
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+static DATA_DIR: OnceLock<String> = OnceLock::new();
+
+/// SelectParams chooses the network of the process, it must be called
+/// before anything reads the parameters
+pub fn select_params(network: Network) -> Result<()> {
+    let chosen = match network {
+        Network::Main => &MAIN_PARAMS,
+        Network::Test => &TEST_PARAMS,
+        Network::Regtest => &REGTEST_PARAMS,
+    };
+    if PARAMS.set(chosen).is_err() && params().network != network {
+        return Err(format_err!("Network {} is already selected", params().name));
+    }
+    info!("use the {} network", chosen.name);
+    Ok(())
+}
+
+/// SelectDataDir chooses the directory of the databases of the process, so that
+/// several nodes can run on one machine, it must be called before anything opens them
+pub fn select_data_dir(dir: &str) -> Result<()> {
+    if DATA_DIR.set(dir.to_string()).is_err() && DATA_DIR.get().map(String::as_str) != Some(dir) {
+        return Err(format_err!(
+            "Data directory {} is already selected",
+            get_base_dir()
+        ));
+    }
+    info!("use the data directory {}", dir);
+    Ok(())
+}
+
+fn get_base_dir() -> &'static str {
+    DATA_DIR.get_or_init(|| DEFAULT_DATA_DIR.to_string())
+}
+
+/// Params returns the parameters of the selected network, main by default
+/// and regtest in the unit tests, whose blocks are mined instantly
+pub fn params() -> &'static ChainParams {
+    PARAMS.get_or_init(|| {
+        if cfg!(test) {
+            &REGTEST_PARAMS
+        } else {
+            &MAIN_PARAMS
+        }
+    })
+}
+
+impl Network {
+    pub fn from_name(name: &str) -> Result<Network> {
+        match name {
+            "main" => Ok(Network::Main),
+            "test" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format_err!("Unknown network {}", name)),
+        }
+    }
+}
+
+impl ChainParams {
+    /// GetDataDir returns the directory of the databases of this network
+    pub fn get_data_dir(&self) -> String {
+        if self.data_subdir.is_empty() {
+            get_base_dir().to_string()
+        } else {
+            format!("{}/{}", get_base_dir(), self.data_subdir)
+        }
+    }
+
+    /// Path returns where the database with the given name lives for this network
+    pub fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.get_data_dir(), name)
+    }
+
+    /// GetBlockSubsidy returns what the coinbase at the given height may create:
+    /// the subsidy halves every subsidy_halving_interval blocks and stops at max_supply
+    pub fn get_block_subsidy(&self, height: usize) -> i32 {
+        let halvings = height / self.subsidy_halving_interval;
+        let halved = if halvings >= 31 {
+            0
+        } else {
+            self.subsidy >> halvings
+        };
+        let left = self.max_supply - self.get_supply_at(height);
+        (halved as i64).min(left).max(0) as i32
+    }
+
+    /// GetSupplyAt returns the coins created by the blocks below the given height
+    pub fn get_supply_at(&self, height: usize) -> i64 {
+        let mut supply = 0;
+        let mut start = 0;
+        let mut subsidy = self.subsidy as i64;
+        while start < height && subsidy > 0 {
+            let blocks = self.subsidy_halving_interval.min(height - start);
+            supply += blocks as i64 * subsidy;
+            start += blocks;
+            subsidy >>= 1;
+        }
+        supply.min(self.max_supply)
+    }
+
+    /// DecodeAddress returns the public key hash of an address of this network
+    pub fn decode_address(&self, address: &str) -> Result<Vec<u8>> {
+        let decoded = Address::decode(address)
+            .map_err(|e| format_err!("Invalid address {}: {:?}", address, e))?;
+        if decoded.network != self.address_network {
+            return Err(format_err!(
+                "Address {} is not a {} network address",
+                address,
+                self.name
+            ));
+        }
+        Ok(decoded.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::pow::compact_to_target;
+
+    #[test]
+    fn test_networks_are_apart() {
+        let networks = [&MAIN_PARAMS, &TEST_PARAMS, &REGTEST_PARAMS];
+        for (i, a) in networks.iter().enumerate() {
+            for b in &networks[i + 1..] {
+                assert_ne!(a.magic, b.magic);
+                assert_ne!(a.path("blocks"), b.path("blocks"));
+                assert_ne!(a.default_port, b.default_port);
+            }
+        }
+        assert!(MAIN_PARAMS
+            .decode_address("3NDb6QpAAxooUZc2yEEkCaL3FD3PtKq3t2")
+            .is_ok());
+        assert!(TEST_PARAMS
+            .decode_address("3NDb6QpAAxooUZc2yEEkCaL3FD3PtKq3t2")
+            .is_err());
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let params = &MAIN_PARAMS;
+        let interval = params.subsidy_halving_interval;
+        assert_eq!(params.get_block_subsidy(0), 100);
+        assert_eq!(params.get_block_subsidy(interval - 1), 100);
+        assert_eq!(params.get_block_subsidy(interval), 50);
+        assert_eq!(params.get_block_subsidy(2 * interval + 1), 25);
+        assert_eq!(params.get_supply_at(interval + 1), 100_050);
+
+        let mut supply = 0;
+        for height in 0..40 * interval {
+            assert_eq!(params.get_supply_at(height), supply);
+            supply += params.get_block_subsidy(height) as i64;
+        }
+        assert_eq!(supply, params.max_supply);
+        assert_eq!(params.get_block_subsidy(usize::MAX), 0);
+    }
+
+    #[test]
+    fn test_genesis_blocks() {
+        for params in [&MAIN_PARAMS, &TEST_PARAMS, &REGTEST_PARAMS] {
+            let genesis = Block::new_genesis_block(params).unwrap();
+            assert_eq!(genesis.get_hash(), params.genesis_hash);
+            assert!(genesis.check_merkle_root().unwrap());
+            let target = compact_to_target(params.pow_limit_bits).unwrap();
+            assert!(genesis.get_header().meets_target(&target).unwrap());
+        }
+    }
+}
@@ -1,11 +1,15 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::process::exit;
 
-use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use clap::{arg, ArgAction, Command};
+use failure::format_err;
 
 use crate::blockchain::Blockchain;
+use crate::chainparams::{params, select_data_dir, select_params, Network};
 use crate::ed25519::Wallets;
 use crate::errors::Result;
+use crate::peer::{unix_time, BanList, DEFAULT_BAN_TIME};
+use crate::server::Server;
 use crate::transaction::Transaction;
 use crate::utxoset::UTXOSet;
 
@@ -20,7 +24,22 @@ impl Cli {
         let matches = Command::new("blockchain-rust-demo")
             .version("0.1")
             .about("sada.@sa.sa")
+            .arg(
+                arg!(--network <NETWORK>"'the network to use, main by default'")
+                    .value_parser(["main", "test", "regtest"])
+                    .global(true),
+            )
+            .arg(
+                arg!(--datadir <DIR>"'the directory of the databases, data by default'")
+                    .global(true),
+            )
             .subcommand(Command::new("printchain").about("print al the chain blocks"))
+            .subcommand(
+                Command::new("printblock")
+                    .about("print the main chain block at a height")
+                    .arg(arg!(<HEIGHT>"'the height of the block'")),
+            )
+            .subcommand(Command::new("supply").about("print the coins issued up to the tip"))
             .subcommand(Command::new("createwallet").about("create a wallet"))
             .subcommand(Command::new("listaddresses").about("list all addresses"))
             .subcommand(Command::new("reindex").about("reindex UTXO"))
@@ -30,27 +49,61 @@ impl Cli {
                     .arg(arg!(<ADDRESS>"'The Address it get balance for'")),
             )
             .subcommand(
-                Command::new("create")
-                    .about("Create new blockchain")
-                    .arg(arg!(<ADDRESS>"'The address to send gensis block reqward to'")),
+                Command::new("create").about("Create new blockchain from the genesis block"),
+            )
+            .subcommand(
+                Command::new("generate")
+                    .about("mine blocks with only a coinbase")
+                    .arg(arg!(<ADDRESS>"'the address the rewards are sent to'"))
+                    .arg(arg!([COUNT]"'the number of blocks to mine, 1 by default'")),
             )
             .subcommand(
                 Command::new("send")
                     .about("send in the blockchain")
                     .arg(arg!(<FROM>"'Source wallet address'"))
                     .arg(arg!(<TO>"'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>"'Amount of wallet ad'")),
+                    .arg(arg!(<AMOUNT>"'Amount of wallet ad'"))
+                    .arg(arg!(--fee <FEE>"'fee paid to the miner, 0 by default'"))
+                    .arg(arg!(--node <NODE>"'relay the transaction to a running node instead of mining it'")),
             )
+            .subcommand(
+                Command::new("startnode")
+                    .about("start the node server")
+                    .arg(arg!([PORT]"'the port server bind to locally, the network port by default'"))
+                    .arg(arg!(--bind <IP>"'the ip the server listens on, all interfaces by default'"))
+                    .arg(arg!(--external <NODE>"'the host:port other nodes reach this node at, not advertised by default'"))
+                    .arg(arg!(--miner <ADDRESS>"'enable mining mode and send reward to ADDRESS'"))
+                    .arg(
+                        arg!(--seed <NODE>"'node to bootstrap the peer discovery from, can be repeated'")
+                            .action(ArgAction::Append),
+                    ),
+            )
+            .subcommand(Command::new("listbanned").about("list the banned peers"))
+            .subcommand(
+                Command::new("setban")
+                    .about("ban or unban a peer")
+                    .arg(arg!(<IP>"'the peer ip, like 127.0.0.1'"))
+                    .arg(arg!(<COMMAND>"'add or remove'").value_parser(["add", "remove"]))
+                    .arg(arg!(--time <SECONDS>"'how long the ban lasts, one day by default'")),
+            )
+            .subcommand(Command::new("clearbanned").about("unban all peers"))
             .get_matches();
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
+        if let Some(network) = matches.get_one::<String>("network") {
+            select_params(Network::from_name(network)?)?;
+        }
+        if let Some(dir) = matches.get_one::<String>("datadir") {
+            select_data_dir(dir)?;
+        }
+
+        if matches.subcommand_matches("createwallet").is_some() {
             let mut ws = Wallets::new()?;
             let address = ws.create_wallet();
             ws.save_all()?;
             println!("Success: address {}", address)
         }
 
-        if let Some(_) = matches.subcommand_matches("reindex") {
+        if matches.subcommand_matches("reindex").is_some() {
             let bc = Blockchain::new()?;
             let utxo_set = UTXOSet { blockchain: bc };
             utxo_set.reindex()?;
@@ -58,7 +111,7 @@ impl Cli {
             println!("Done! There are {} transactions in the UTXO set. ", count);
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
+        if matches.subcommand_matches("listaddresses").is_some() {
             let ws = Wallets::new()?;
             let addresses = ws.get_all_address();
             println!("addresses: ");
@@ -67,39 +120,55 @@ impl Cli {
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
+        if matches.subcommand_matches("create").is_some() {
+            let bc = Blockchain::create_blockchain()?;
+            UTXOSet::new(bc)?;
+            println!("create blockchain");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let address = String::from(address);
-                let bc = Blockchain::create_blockchain(address.clone())?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                utxo_set.reindex()?;
-                println!("create blockchain");
+                let pub_key_hash = params().decode_address(address)?;
+                let bc = Blockchain::new()?;
+                let utxo_set = UTXOSet::new(bc)?;
+                let (blance, immature) = utxo_set.get_balance(&pub_key_hash)?;
+                println!("Balance of '{}'; {}", address, blance);
+                println!("Immature coinbase funds: {}", immature);
             }
             // else {
             //     println!("Not printing testing lists...");
             // }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        if let Some(matches) = matches.subcommand_matches("generate") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let pub_key_hash = Address::decode(address).unwrap().body;
+                params().decode_address(address)?;
+                let count: usize = match matches.get_one::<String>("COUNT") {
+                    Some(count) => count.parse()?,
+                    None => 1,
+                };
                 let bc = Blockchain::new()?;
-                // let utxos = bc.find_utxo(&pub_key_hash);
-                let utxo_set = UTXOSet { blockchain: bc };
-                let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
-                let mut blance = 0;
-
-                for out in utxos.outputs {
-                    blance += out.value;
+                let mut utxo_set = UTXOSet::new(bc)?;
+                for _ in 0..count {
+                    let height = utxo_set.blockchain.get_best_height()? + 1;
+                    let cbtx = Transaction::new_coinbase(
+                        address.clone(),
+                        String::new(),
+                        height as usize,
+                        0,
+                    )?;
+                    let new_block = utxo_set.blockchain.mine_block(vec![cbtx])?;
+                    utxo_set.update(&new_block)?;
+                    println!(
+                        "generated block {} at height {}",
+                        new_block.get_hash(),
+                        height
+                    );
                 }
-                println!("Balance of '{}'; {}", address, blance)
             }
-            // else {
-            //     println!("Not printing testing lists...");
-            // }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
+        if let Some(matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.get_one::<String>("FROM") {
                 address
             } else {
@@ -121,18 +190,139 @@ impl Cli {
                 exit(1)
             };
 
-            let mut bc = Blockchain::new()?;
-            let mut utxo_set = UTXOSet { blockchain: bc };
-            let tx = Transaction::new_utxo(from, to, amount, &utxo_set)?;
-            let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
-            let new_block = utxo_set.blockchain.add_block(vec![cbtx, tx])?;
+            let fee: i32 = match matches.get_one::<String>("fee") {
+                Some(fee) => fee.parse()?,
+                None => 0,
+            };
+
+            let wallets = Wallets::new()?;
+            let wallet = match wallets.get_wallet(from) {
+                Some(w) => w,
+                None => return Err(format_err!("from wallet not found")),
+            };
+            if wallets.get_wallet(to).is_none() {
+                return Err(format_err!("to wallet not found"));
+            }
+            let pub_key_hash = params().decode_address(from)?;
+
+            // the running node holds the block database, so its outputs are asked for
+            if let Some(node) = matches.get_one::<String>("node") {
+                let spendable = Server::get_spendable_outputs(node, &pub_key_hash)?;
+                let tx = Transaction::new_utxo(wallet, to, amount, fee, spendable)?;
+                Server::send_transaction(node, &tx)?;
+                println!("success: tx {} sent to {}", tx.id, node);
+                return Ok(());
+            }
+            let bc = Blockchain::new()?;
+            let mut utxo_set = UTXOSet::new(bc)?;
+            let spendable = utxo_set.get_spendable_outputs(&pub_key_hash)?;
+            let tx = Transaction::new_utxo(wallet, to, amount, fee, spendable)?;
+            let height = utxo_set.blockchain.get_best_height()? + 1;
+            let cbtx = Transaction::new_coinbase(
+                from.to_string(),
+                String::from("reward!"),
+                height as usize,
+                fee,
+            )?;
+            let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
 
             utxo_set.update(&new_block)?;
 
             println!("success");
         }
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
+        if let Some(matches) = matches.subcommand_matches("startnode") {
+            let port: u16 = match matches.get_one::<String>("PORT") {
+                Some(port) => port.parse()?,
+                None => params().default_port.parse()?,
+            };
+            let bind: IpAddr = match matches.get_one::<String>("bind") {
+                Some(ip) => ip.parse()?,
+                None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            };
+            let external = match matches.get_one::<String>("external") {
+                Some(node) => node.as_str(),
+                None => "",
+            };
+            let miner = match matches.get_one::<String>("miner") {
+                Some(address) => {
+                    params().decode_address(address)?;
+                    address.as_str()
+                }
+                None => "",
+            };
+            let seeds: Vec<String> = match matches.get_many::<String>("seed") {
+                Some(seeds) => seeds.cloned().collect(),
+                None => params().seeds.iter().map(|s| s.to_string()).collect(),
+            };
+            let bind_address = SocketAddr::new(bind, port).to_string();
+            println!("Start {} node on {}...", params().name, bind_address);
+            let bc = Blockchain::new()?;
+            let utxo_set = UTXOSet::new(bc)?;
+            let server = Server::new(&bind_address, external, miner, seeds, utxo_set)?;
+            server.start_server()?;
+        }
+
+        let ban_list = BanList::new(&params().path("banlist"));
+        if matches.subcommand_matches("listbanned").is_some() {
+            let now = unix_time();
+            for (addr, until) in ban_list.get_banned()? {
+                println!("{} banned for {} more seconds", addr, until - now);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("setban") {
+            if let Some(ip) = matches.get_one::<String>("IP") {
+                let addr = ip.parse::<IpAddr>()?.to_string();
+                if matches.get_one::<String>("COMMAND").map(|c| c.as_str()) == Some("add") {
+                    let time: u64 = match matches.get_one::<String>("time") {
+                        Some(time) => time.parse()?,
+                        None => DEFAULT_BAN_TIME,
+                    };
+                    let until = match unix_time().checked_add(time) {
+                        Some(until) => until,
+                        None => return Err(format_err!("Ban time {} is too large", time)),
+                    };
+                    ban_list.ban(&addr, until)?;
+                    println!("Success: {} banned for {} seconds", addr, time);
+                } else if ban_list.unban(&addr)? {
+                    println!("Success: {} unbanned", addr);
+                } else {
+                    println!("{} is not banned", addr);
+                }
+            }
+        }
+
+        if matches.subcommand_matches("clearbanned").is_some() {
+            ban_list.clear()?;
+            println!("Success: ban list cleared");
+        }
+
+        if matches.subcommand_matches("supply").is_some() {
+            let bc = Blockchain::new()?;
+            let height = bc.get_best_height()?;
+            let next_height = (height + 1) as usize;
+            println!(
+                "Supply at height {}: {} issued, {} scheduled, {} at most",
+                height,
+                bc.get_issued_supply()?,
+                params().get_supply_at(next_height),
+                params().max_supply
+            );
+            println!(
+                "Next block subsidy: {}",
+                params().get_block_subsidy(next_height)
+            );
+        }
+
+        if let Some(matches) = matches.subcommand_matches("printblock") {
+            if let Some(height) = matches.get_one::<String>("HEIGHT") {
+                let bc = Blockchain::new()?;
+                println!("{:#?}", bc.get_block_by_height(height.parse()?)?);
+            }
+        }
+
+        if matches.subcommand_matches("printchain").is_some() {
             let bc = Blockchain::new()?;
             for b in bc.iter() {
                 println!("{:#?}", b);
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use crate::chainparams::params;
 use crate::errors::Result;
 use bitcoincash_addr::{Address, HashType, Scheme};
 use crypto::{digest::Digest, ed25519, ripemd160::Ripemd160, sha2::Sha256};
@@ -15,7 +16,7 @@ pub struct Wallet {
 }
 
 impl Wallet {
-    fn new() -> Self {
+    pub fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
         OsRng.fill_bytes(&mut key);
         let (secret_key, public_key) = ed25519::keypair(&key);
@@ -28,14 +29,14 @@ impl Wallet {
         }
     }
 
-    fn get_address(&self) -> String {
+    pub fn get_address(&self) -> String {
         let mut pub_hash = self.public_key.clone();
         Self::hash_pub_key(&mut pub_hash);
         let address = Address {
             body: pub_hash,
             scheme: Scheme::Base58,
             hash_type: HashType::Script,
-            ..Default::default()
+            network: params().address_network.clone(),
         };
         // 0 O 1
         address.encode().unwrap()
@@ -62,11 +63,11 @@ impl Wallets {
             wallets: HashMap::<String, Wallet>::new(),
         };
 
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(params().path("wallets"))?;
         for item in db.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
+            let wallet = bincode::deserialize(&i.1)?;
             wlt.wallets.insert(address, wallet);
         }
 
@@ -84,7 +85,7 @@ impl Wallets {
 
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::new();
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone())
         }
         addresses
@@ -95,7 +96,7 @@ impl Wallets {
     }
 
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(params().path("wallets"))?;
 
         for (address, wallet) in &self.wallets {
             let data = bincode::serialize(&wallet)?;
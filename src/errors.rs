@@ -1 +1,27 @@
+use std::fmt;
+
+use failure::Fail;
+
 pub type Result<T> = std::result::Result<T, failure::Error>;
+
+/// Misbehavior is returned when a peer sent data that breaks the protocol or the consensus rules
+#[derive(Debug)]
+pub struct Misbehavior {
+    pub score: u32,
+    pub reason: String,
+}
+
+impl Misbehavior {
+    /// Error wraps a misbehavior into the error type the handlers return
+    pub fn error(score: u32, reason: String) -> failure::Error {
+        Misbehavior { score, reason }.into()
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (misbehavior +{})", self.reason, self.score)
+    }
+}
+
+impl Fail for Misbehavior {}
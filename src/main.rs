@@ -1,15 +1,26 @@
 use crate::errors::Result;
 use cli::Cli;
+mod assembler;
 mod block;
 mod blockchain;
+mod chainparams;
 mod cli;
 mod ed25519;
 mod errors;
+mod mempool;
+mod miner;
+mod orphan;
+mod peer;
+mod pow;
 mod server;
+#[cfg(test)]
+mod testutil;
+mod timedata;
 mod transaction;
 mod txs;
 mod utxoset;
 fn main() -> Result<()> {
+    env_logger::init();
     let mut cli = Cli::new()?;
     cli.run()?;
     Ok(())
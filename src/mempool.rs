@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+
+use failure::format_err;
+use log::info;
+
+use crate::block::Block;
+use crate::chainparams::params;
+use crate::errors::{Misbehavior, Result};
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+
+/// Mempool keeps the validated transactions that are waiting to be mined
+#[derive(Default)]
+pub struct Mempool {
+    txs: HashMap<String, Transaction>,
+    /// fees maps a txid to what its inputs pay more than its outputs
+    fees: HashMap<String, i64>,
+    /// spent maps every outpoint used by a mempool transaction to the spending txid
+    spent: HashMap<(String, i32), String>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.txs.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.txs.get(txid)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// GetTransactions returns the mempool transactions, every parent before its children
+    pub fn get_transactions(&self) -> Vec<Transaction> {
+        let mut txids: Vec<&String> = self.txs.keys().collect();
+        txids.sort();
+        let mut sorted = Vec::new();
+        let mut visited = HashSet::new();
+        for txid in txids {
+            self.visit_parents_first(txid, &mut visited, &mut sorted);
+        }
+        sorted
+    }
+
+    fn visit_parents_first(
+        &self,
+        txid: &str,
+        visited: &mut HashSet<String>,
+        sorted: &mut Vec<Transaction>,
+    ) {
+        if !visited.insert(txid.to_string()) {
+            return;
+        }
+        if let Some(tx) = self.txs.get(txid) {
+            for parent in self.get_parents(tx) {
+                self.visit_parents_first(&parent, visited, sorted);
+            }
+            sorted.push(tx.clone());
+        }
+    }
+
+    /// GetParents returns the ids of the mempool transactions whose outputs the transaction spends
+    pub fn get_parents(&self, tx: &Transaction) -> HashSet<String> {
+        tx.vin
+            .iter()
+            .filter(|vin| self.txs.contains_key(&vin.txid))
+            .map(|vin| vin.txid.clone())
+            .collect()
+    }
+
+    /// IsSpent tells whether a mempool transaction already spends the outpoint
+    pub fn is_spent(&self, txid: &str, vout: i32) -> bool {
+        self.spent.contains_key(&(txid.to_string(), vout))
+    }
+
+    pub fn get_fee(&self, txid: &str) -> i64 {
+        self.fees.get(txid).cloned().unwrap_or(0)
+    }
+
+    /// Add validates a transaction against the UTXO set and the other
+    /// mempool entries and stores it
+    pub fn add(&mut self, tx: Transaction, utxo: &UTXOSet) -> Result<()> {
+        let fee = self.check_transaction(&tx, utxo)?;
+        for vin in &tx.vin {
+            self.spent
+                .insert((vin.txid.clone(), vin.vout), tx.id.clone());
+        }
+        info!(
+            "add tx {} paying a fee of {} to the mempool, size: {}",
+            tx.id,
+            fee,
+            self.txs.len() + 1
+        );
+        self.fees.insert(tx.id.clone(), fee);
+        self.txs.insert(tx.id.clone(), tx);
+        Ok(())
+    }
+
+    /// RemoveBlockTransactions drops the transactions confirmed by a block
+    /// and those that conflict with it, together with their descendants
+    pub fn remove_block_transactions(&mut self, block: &Block) {
+        for tx in block.get_transaction() {
+            self.remove(&tx.id);
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if let Some(txid) = self.spent.get(&(vin.txid.clone(), vin.vout)).cloned() {
+                    self.remove_with_descendants(&txid);
+                }
+            }
+        }
+    }
+
+    /// RemoveWithDescendants drops a transaction and the ones spending its outputs
+    fn remove_with_descendants(&mut self, txid: &str) {
+        let outputs = match self.txs.get(txid) {
+            Some(tx) => tx.vout.len(),
+            None => return,
+        };
+        self.remove(txid);
+        for vout in 0..outputs {
+            if let Some(child) = self.spent.get(&(txid.to_string(), vout as i32)).cloned() {
+                self.remove_with_descendants(&child);
+            }
+        }
+    }
+
+    fn remove(&mut self, txid: &str) {
+        self.fees.remove(txid);
+        if let Some(tx) = self.txs.remove(txid) {
+            for vin in &tx.vin {
+                self.spent.remove(&(vin.txid.clone(), vin.vout));
+            }
+        }
+    }
+
+    /// CheckTransaction validates a transaction and returns its fee
+    fn check_transaction(&self, tx: &Transaction, utxo: &UTXOSet) -> Result<i64> {
+        if self.contains(&tx.id) {
+            return Err(format_err!("tx {} is already in the mempool", tx.id));
+        }
+        if tx.is_coinbase() {
+            return Err(Misbehavior::error(
+                100,
+                format!("tx {} is a coinbase", tx.id),
+            ));
+        }
+        if tx.vin.is_empty() || tx.vout.is_empty() {
+            return Err(Misbehavior::error(
+                100,
+                format!("tx {} has no inputs or outputs", tx.id),
+            ));
+        }
+
+        let size = tx.get_size()?;
+        if size > params().max_tx_size {
+            return Err(Misbehavior::error(
+                100,
+                format!("tx {} is too large: {} bytes", tx.id, size),
+            ));
+        }
+
+        if !tx.check_id()? {
+            return Err(Misbehavior::error(
+                100,
+                format!("tx {} has a wrong id", tx.id),
+            ));
+        }
+
+        // the transaction can at best be mined in the next block
+        let next_height = (utxo.blockchain.get_best_height()? + 1) as usize;
+        let mut seen = HashSet::new();
+        let mut spent = Vec::new();
+        for vin in &tx.vin {
+            let outpoint = (vin.txid.clone(), vin.vout);
+            if !seen.insert(outpoint.clone()) {
+                return Err(Misbehavior::error(
+                    100,
+                    format!("tx {} spends {}:{} twice", tx.id, vin.txid, vin.vout),
+                ));
+            }
+            if let Some(other) = self.spent.get(&outpoint) {
+                return Err(format_err!(
+                    "tx {} conflicts with mempool tx {} on {}:{}",
+                    tx.id,
+                    other,
+                    vin.txid,
+                    vin.vout
+                ));
+            }
+            // a parent still in the mempool is mined first, in the same block at the latest
+            let parent_output = self
+                .txs
+                .get(&vin.txid)
+                .and_then(|parent| parent.vout.get(usize::try_from(vin.vout).ok()?))
+                .map(|out| (out.clone(), true));
+            let output = match parent_output {
+                Some(output) => Some(output),
+                None => utxo.get_output(&vin.txid, vin.vout, next_height)?,
+            };
+            match output {
+                Some((out, true)) => spent.push(out),
+                Some((_, false)) => {
+                    return Err(format_err!(
+                        "tx {} spends {}:{} which is an immature coinbase output",
+                        tx.id,
+                        vin.txid,
+                        vin.vout
+                    ))
+                }
+                None => {
+                    return Err(format_err!(
+                        "tx {} spends {}:{} which is neither in the UTXO set nor in the mempool",
+                        tx.id,
+                        vin.txid,
+                        vin.vout
+                    ))
+                }
+            }
+        }
+
+        let input_value: i64 = spent.iter().map(|out| out.value as i64).sum();
+        let mut output_value: i64 = 0;
+        for out in &tx.vout {
+            if out.value <= 0 {
+                return Err(Misbehavior::error(
+                    100,
+                    format!("tx {} has a non positive output", tx.id),
+                ));
+            }
+            output_value += out.value as i64;
+        }
+        if output_value > input_value {
+            return Err(Misbehavior::error(
+                100,
+                format!(
+                    "tx {} spends {} but only has {} in its inputs",
+                    tx.id, output_value, input_value
+                ),
+            ));
+        }
+
+        if !tx.verify_spent_outputs(&spent)? {
+            return Err(Misbehavior::error(
+                100,
+                format!("tx {} has an invalid signature", tx.id),
+            ));
+        }
+        Ok(input_value - output_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ed25519::Wallet;
+    use crate::testutil::{
+        funded_chain, mine, mine_on, misbehavior_score, new_chain, output, pay, spendable,
+    };
+
+    #[test]
+    fn test_conflicting_spends() {
+        let (_dir, utxo, wallet) = funded_chain("mempool-conflict", 1);
+        let coin = &spendable(&utxo, &wallet)[0];
+        let tx = pay(&wallet, 30, coin);
+        let conflict = pay(&wallet, 40, coin);
+
+        let mut mempool = Mempool::new();
+        mempool.add(tx.clone(), &utxo).unwrap();
+        assert!(mempool.is_spent(&coin.txid, coin.vout));
+        assert_eq!(mempool.get_fee(&tx.id), 1);
+        assert!(mempool.add(tx, &utxo).is_err());
+        // a double spend is not punished, the peer may have seen it first
+        assert_eq!(
+            misbehavior_score(mempool.add(conflict.clone(), &utxo)),
+            None
+        );
+        assert!(!mempool.contains(&conflict.id));
+    }
+
+    #[test]
+    fn test_chained_transactions() {
+        let (_dir, utxo, wallet) = funded_chain("mempool-chain", 1);
+        let parent = pay(&wallet, 30, &spendable(&utxo, &wallet)[0]);
+        let child = pay(&wallet, 50, &output(&parent, 1));
+        let grandchild = pay(&wallet, 10, &output(&child, 1));
+
+        let mut mempool = Mempool::new();
+        // a transaction waits for its unconfirmed parent
+        assert!(mempool.add(child.clone(), &utxo).is_err());
+        mempool.add(parent.clone(), &utxo).unwrap();
+        assert!(mempool.add(grandchild.clone(), &utxo).is_err());
+        mempool.add(child.clone(), &utxo).unwrap();
+        mempool.add(grandchild.clone(), &utxo).unwrap();
+
+        assert_eq!(
+            mempool.get_parents(&grandchild),
+            HashSet::from([child.id.clone()])
+        );
+        let ids: Vec<String> = mempool
+            .get_transactions()
+            .into_iter()
+            .map(|tx| tx.id)
+            .collect();
+        assert_eq!(ids, vec![parent.id, child.id, grandchild.id]);
+    }
+
+    #[test]
+    fn test_bad_signatures() {
+        let (_dir, utxo, wallet) = funded_chain("mempool-signature", 1);
+        let coin = &spendable(&utxo, &wallet)[0];
+        let mut mempool = Mempool::new();
+
+        let mut tampered = pay(&wallet, 30, coin);
+        tampered.vin[0].signature[0] ^= 0xff;
+        assert_eq!(misbehavior_score(mempool.add(tampered, &utxo)), Some(100));
+
+        // a key that does not own the output cannot spend it
+        let thief = Wallet::new();
+        let stolen = pay(&thief, 30, coin);
+        assert_eq!(misbehavior_score(mempool.add(stolen, &utxo)), Some(100));
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_overspend() {
+        let (_dir, utxo, wallet) = funded_chain("mempool-overspend", 1);
+        let coin = &spendable(&utxo, &wallet)[0];
+        let mut tx = pay(&wallet, 30, coin);
+        tx.vout[0].value += coin.output.value;
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+        }
+        tx.id = tx.hash().unwrap();
+        tx.sign(&wallet.secret_key, std::slice::from_ref(&coin.output))
+            .unwrap();
+
+        let mut mempool = Mempool::new();
+        assert_eq!(misbehavior_score(mempool.add(tx, &utxo)), Some(100));
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_remove_block_transactions() {
+        let (_dir, utxo, wallet) = funded_chain("mempool-block", 3);
+        let coins = spendable(&utxo, &wallet);
+        let tx = pay(&wallet, 30, &coins[0]);
+        let child = pay(&wallet, 50, &output(&tx, 1));
+        let confirmed = pay(&wallet, 30, &coins[1]);
+        let unrelated = pay(&wallet, 30, &coins[2]);
+        let mut mempool = Mempool::new();
+        for tx in [&tx, &child, &confirmed, &unrelated] {
+            mempool.add(tx.clone(), &utxo).unwrap();
+        }
+
+        // the block confirms one transaction and double spends the parent of another
+        let conflict = pay(&wallet, 40, &coins[0]);
+        let tip = utxo.blockchain.get_tip_hash();
+        let miner = Wallet::new().get_address();
+        let block = mine_on(&utxo.blockchain, &tip, &miner, vec![conflict, confirmed]);
+        mempool.remove_block_transactions(&block);
+
+        let ids: Vec<String> = mempool
+            .get_transactions()
+            .into_iter()
+            .map(|tx| tx.id)
+            .collect();
+        assert_eq!(ids, vec![unrelated.id]);
+        assert!(!mempool.is_spent(&coins[0].txid, coins[0].vout));
+        assert!(!mempool.is_spent(&tx.id, 1));
+    }
+
+    #[test]
+    fn test_reject_oversized_transaction() {
+        let (_dir, utxo, wallet) = funded_chain("mempool-size", 1);
+        let mut tx = pay(&wallet, 30, &spendable(&utxo, &wallet)[0]);
+        tx.vin[0].pub_key.resize(params().max_tx_size, 0);
+
+        let mut mempool = Mempool::new();
+        let e = mempool.add(tx, &utxo).unwrap_err();
+        assert!(e.to_string().contains("too large"));
+        assert_eq!(misbehavior_score::<()>(Err(e)), Some(100));
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_immature_coinbase_spend() {
+        let (_dir, mut utxo) = new_chain("mempool-maturity");
+        let wallet = Wallet::new();
+        let miner = Wallet::new().get_address();
+        let block = mine(&mut utxo, &wallet.get_address(), Vec::new());
+        let tx = pay(&wallet, 30, &output(&block.get_transaction()[0], 0));
+        for _ in 2..params().coinbase_maturity {
+            mine(&mut utxo, &miner, Vec::new());
+        }
+
+        // the next block comes one block too early, the peer is not to blame
+        let mut mempool = Mempool::new();
+        let e = mempool.add(tx.clone(), &utxo).unwrap_err();
+        assert!(e.to_string().contains("immature coinbase output"));
+        assert_eq!(misbehavior_score::<()>(Err(e)), None);
+
+        mine(&mut utxo, &miner, Vec::new());
+        mempool.add(tx.clone(), &utxo).unwrap();
+        assert!(mempool.contains(&tx.id));
+    }
+}
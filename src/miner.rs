@@ -0,0 +1,232 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use failure::format_err;
+use log::info;
+
+use crate::block::Block;
+use crate::errors::Result;
+use crate::pow::compact_to_target;
+use crate::timedata::get_adjusted_time;
+
+/// CHECK_INTERVAL is how many hashes a worker tries between two looks at the stop flags
+const CHECK_INTERVAL: u32 = 1 << 12;
+
+/// Miner searches the proof of work of a block template on several threads
+pub struct Miner {
+    threads: u32,
+    /// max_nonce is the last nonce tried before the extra nonce is rolled
+    max_nonce: u32,
+}
+
+impl Miner {
+    pub fn new(threads: u32) -> Miner {
+        Miner {
+            threads: threads.max(1),
+            max_nonce: u32::MAX,
+        }
+    }
+
+    /// DefaultThreads returns the number of CPUs of the machine
+    pub fn default_threads() -> u32 {
+        thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1)
+    }
+
+    /// Mine runs the proof of work on the template until a block is found or
+    /// cancel is set, in which case it returns None.
+    ///
+    /// Every worker tries a different share of the nonces. When all of them are
+    /// used, the extra nonce of the coinbase is rolled and the search starts again.
+    pub fn mine(&self, mut block: Block, cancel: &AtomicBool) -> Result<Option<Block>> {
+        let target = compact_to_target(block.get_header().bits)?;
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let mut extra_nonce = 0;
+        info!(
+            "Minnning the block at height {} with {} threads",
+            block.get_height(),
+            self.threads
+        );
+        loop {
+            let found = AtomicBool::new(false);
+            let nonce = Mutex::new(None);
+            let header = block.get_header();
+            thread::scope(|s| -> Result<()> {
+                let mut workers = Vec::new();
+                for worker in 0..self.threads {
+                    let (found, nonce, hashes, target) = (&found, &nonce, &hashes, &target);
+                    let mut header = header.clone();
+                    workers.push(s.spawn(move || -> Result<()> {
+                        let mut n = worker as u64;
+                        let mut count = 0;
+                        while n <= self.max_nonce as u64 {
+                            header.nonce = n as u32;
+                            count += 1;
+                            if header.meets_target(target)? {
+                                found.store(true, Ordering::Relaxed);
+                                *nonce.lock().unwrap() = Some(header.nonce);
+                                break;
+                            }
+                            n += self.threads as u64;
+                            if count % CHECK_INTERVAL == 0
+                                && (found.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed))
+                            {
+                                break;
+                            }
+                        }
+                        hashes.fetch_add(count as u64, Ordering::Relaxed);
+                        Ok(())
+                    }));
+                }
+                for worker in workers {
+                    match worker.join() {
+                        Ok(result) => result?,
+                        Err(_) => return Err(format_err!("Mining thread panicked")),
+                    }
+                }
+                Ok(())
+            })?;
+
+            if let Some(nonce) = nonce.into_inner().unwrap() {
+                block.seal(nonce)?;
+                log_hashrate(&hashes, start);
+                return Ok(Some(block));
+            }
+            if cancel.load(Ordering::Relaxed) {
+                info!("Mining of block at height {} cancelled", block.get_height());
+                log_hashrate(&hashes, start);
+                return Ok(None);
+            }
+            extra_nonce += 1;
+            info!("Nonce space exhausted, roll extra nonce to {}", extra_nonce);
+            block.set_extra_nonce(extra_nonce)?;
+            // the timestamp only moves forward so it stays after the median time past
+            block.set_timestamp(block.get_timestamp().max(get_adjusted_time()));
+        }
+    }
+}
+
+fn log_hashrate(hashes: &AtomicU64, start: Instant) {
+    let hashes = hashes.load(Ordering::Relaxed);
+    let secs = start.elapsed().as_secs_f64();
+    let rate = if secs > 0.0 {
+        hashes as f64 / secs
+    } else {
+        0.0
+    };
+    info!(
+        "Tried {} hashes in {:.2}s, hashrate: {:.0} H/s",
+        hashes, secs, rate
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chainparams::params;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_mine() {
+        let coinbase =
+            Transaction::new_coinbase(String::from(params().genesis_address), String::new(), 1, 0)
+                .unwrap();
+        let template = Block::new_template(
+            vec![coinbase],
+            String::from("prev"),
+            1,
+            params().pow_limit_bits,
+        )
+        .unwrap();
+        let block = Miner::new(4)
+            .mine(template.clone(), &AtomicBool::new(false))
+            .unwrap()
+            .unwrap();
+        assert!(block.check_proof_of_work().unwrap());
+        assert_eq!(block.get_hash(), block.get_header().hash().unwrap());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let coinbase =
+            Transaction::new_coinbase(String::from(params().genesis_address), String::new(), 1, 0)
+                .unwrap();
+        // only a hash of zero meets this target
+        let template =
+            Block::new_template(vec![coinbase], String::from("prev"), 1, 0x03000001).unwrap();
+        let cancelled = Miner::new(2)
+            .mine(template, &AtomicBool::new(true))
+            .unwrap();
+        assert!(cancelled.is_none());
+    }
+
+    #[test]
+    fn test_nonce_exhaustion() {
+        let coinbase =
+            Transaction::new_coinbase(String::from(params().genesis_address), String::new(), 1, 0)
+                .unwrap();
+        // one hash in 16 meets the target, the first nonce of the first template must not
+        let bits = 0x20100000;
+        let target = compact_to_target(bits).unwrap();
+        let mut template =
+            Block::new_template(vec![coinbase], String::from("prev"), 1, bits).unwrap();
+        while template.get_header().meets_target(&target).unwrap() {
+            template.set_timestamp(template.get_timestamp() - 1);
+        }
+        let miner = Miner {
+            threads: 2,
+            max_nonce: 0,
+        };
+        let block = miner
+            .mine(template.clone(), &AtomicBool::new(false))
+            .unwrap()
+            .unwrap();
+        assert_eq!(block.get_header().nonce, 0);
+        assert!(block.check_proof_of_work().unwrap());
+        // the extra nonce follows the height in the coinbase input
+        let coinbase = &block.get_transaction()[0];
+        assert_ne!(coinbase.vin[0].pub_key[8..16], [0; 8]);
+        assert_eq!(coinbase.get_coinbase_height(), Some(1));
+        assert_ne!(
+            block.get_header().merkle_root,
+            template.get_header().merkle_root
+        );
+        assert!(block.check_merkle_root().unwrap());
+    }
+
+    #[test]
+    fn test_extra_nonce() {
+        let coinbase =
+            Transaction::new_coinbase(String::from(params().genesis_address), String::new(), 1, 0)
+                .unwrap();
+        let mut block = Block::new_template(
+            vec![coinbase],
+            String::from("prev"),
+            1,
+            params().pow_limit_bits,
+        )
+        .unwrap();
+        let root = block.get_header().merkle_root.clone();
+        let txid = block.get_transaction()[0].id.clone();
+        block.set_extra_nonce(1).unwrap();
+        assert_ne!(block.get_header().merkle_root, root);
+        assert_ne!(block.get_transaction()[0].id, txid);
+        assert_eq!(block.get_transaction()[0].get_coinbase_height(), Some(1));
+        assert!(block.check_merkle_root().unwrap());
+
+        // the same reward at another height is another transaction
+        let other =
+            Transaction::new_coinbase(String::from(params().genesis_address), String::new(), 2, 0)
+                .unwrap();
+        assert_ne!(other.id, txid);
+        assert_eq!(other.get_coinbase_height(), Some(2));
+    }
+}
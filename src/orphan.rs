@@ -0,0 +1,121 @@
+use std::collections::{HashMap, VecDeque};
+
+use log::info;
+
+use crate::block::Block;
+
+/// MAX_ORPHAN_BLOCKS bounds the number of blocks waiting for their parent
+const MAX_ORPHAN_BLOCKS: usize = 100;
+
+/// OrphanPool keeps the blocks that arrived before their parent
+#[derive(Default)]
+pub struct OrphanPool {
+    /// blocks maps an orphan hash to the block and the peer that sent it
+    blocks: HashMap<String, (Block, String)>,
+    /// children maps a missing parent hash to the orphans waiting for it
+    children: HashMap<String, Vec<String>>,
+    /// order keeps the orphan hashes oldest first so the oldest gets evicted
+    order: VecDeque<String>,
+}
+
+impl OrphanPool {
+    pub fn new() -> OrphanPool {
+        OrphanPool::default()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Add stores an orphan block, evicting the oldest one when the pool is full
+    pub fn add(&mut self, block: Block, from: &str) {
+        let hash = block.get_hash();
+        if self.contains(&hash) {
+            return;
+        }
+        while self.blocks.len() >= MAX_ORPHAN_BLOCKS {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    info!("orphan pool is full, evict block {}", oldest);
+                    self.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        info!(
+            "add orphan block {} waiting for {}, pool size: {}",
+            hash,
+            block.get_prev_hash(),
+            self.blocks.len() + 1
+        );
+        self.children
+            .entry(block.get_prev_hash())
+            .or_default()
+            .push(hash.clone());
+        self.order.push_back(hash.clone());
+        self.blocks.insert(hash, (block, from.to_string()));
+    }
+
+    /// TakeChildren removes and returns the orphans whose parent is the given block,
+    /// together with the peers that sent them
+    pub fn take_children(&mut self, parent_hash: &str) -> Vec<(Block, String)> {
+        let hashes = self.children.remove(parent_hash).unwrap_or_default();
+        let mut blocks = Vec::new();
+        for hash in hashes {
+            if let Some(entry) = self.blocks.remove(&hash) {
+                self.order.retain(|h| *h != hash);
+                blocks.push(entry);
+            }
+        }
+        blocks
+    }
+
+    fn remove(&mut self, hash: &str) {
+        if let Some((block, _)) = self.blocks.remove(hash) {
+            let prev_hash = block.get_prev_hash();
+            if let Some(siblings) = self.children.get_mut(&prev_hash) {
+                siblings.retain(|h| h != hash);
+                if siblings.is_empty() {
+                    self.children.remove(&prev_hash);
+                }
+            }
+        }
+        self.order.retain(|h| h != hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chainparams::params;
+
+    #[test]
+    fn test_take_children() {
+        let parent = Block::new_template(
+            Vec::new(),
+            String::from("unknown"),
+            1,
+            params().pow_limit_bits,
+        )
+        .unwrap()
+        .mine()
+        .unwrap();
+        let child = Block::new_template(Vec::new(), parent.get_hash(), 2, params().pow_limit_bits)
+            .unwrap()
+            .mine()
+            .unwrap();
+
+        let mut pool = OrphanPool::new();
+        pool.add(child.clone(), "localhost:3001");
+        pool.add(parent.clone(), "localhost:3002");
+        assert!(pool.contains(&child.get_hash()));
+        assert!(pool.take_children("other").is_empty());
+
+        let children = pool.take_children(&parent.get_hash());
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].0.get_hash(), child.get_hash());
+        assert_eq!(children[0].1, "localhost:3001");
+        assert!(!pool.contains(&child.get_hash()));
+        assert!(pool.contains(&parent.get_hash()));
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    cmp::Reverse,
+    io::Write,
+    net::{IpAddr, Shutdown, TcpStream},
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use failure::format_err;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Result;
+
+/// NODE_NETWORK is set by nodes that store the full chain and serve blocks
+pub const NODE_NETWORK: u64 = 1;
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// RETRY_INTERVAL is how long we wait before dialing again an address that failed
+const RETRY_INTERVAL: u64 = 60;
+/// MAX_FAILURES drops an address from the store after this many failed connection attempts in a row
+const MAX_FAILURES: u32 = 10;
+/// GOOD_PEER_AGE limits the gossiped addresses to peers we talked to recently
+const GOOD_PEER_AGE: u64 = 3 * 60 * 60;
+/// BAN_THRESHOLD is the misbehavior score at which a peer gets banned
+pub const BAN_THRESHOLD: u32 = 100;
+pub const DEFAULT_BAN_TIME: u64 = 24 * 60 * 60;
+/// MAX_TX_PER_MINUTE is how many transactions a peer may relay to us per minute
+pub const MAX_TX_PER_MINUTE: u32 = 100;
+
+/// Peer is a long-lived connection to another node together with what we learned about it
+pub struct Peer {
+    /// addr is the address the session is known by: the dialed one for outbound
+    /// peers and the socket address for inbound ones
+    pub addr: String,
+    /// ip is where the connection comes from, misbehavior is banned by ip
+    pub ip: IpAddr,
+    /// listen_addr is the address the peer accepts connections on, as it told us
+    /// in its version message, only used to gossip it and to avoid dialing it again
+    pub listen_addr: String,
+    pub inbound: bool,
+    pub version: i32,
+    pub user_agent: String,
+    pub services: u64,
+    pub best_height: i32,
+    pub last_seen: u64,
+    pub version_received: bool,
+    pub verack_received: bool,
+    pub misbehavior: u32,
+    pub tx_window_start: u64,
+    pub tx_count: u32,
+    sender: Sender<Vec<u8>>,
+    stream: TcpStream,
+}
+
+impl Peer {
+    /// New wraps a connected stream and starts the thread that writes to it
+    pub fn new(addr: &str, stream: TcpStream, inbound: bool) -> Result<Peer> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let peer_addr = addr.to_string();
+        thread::spawn(move || {
+            for data in receiver {
+                if let Err(e) = writer.write_all(&data) {
+                    info!("failed to write to peer {}: {}", peer_addr, e);
+                    break;
+                }
+            }
+            let _ = writer.shutdown(Shutdown::Both);
+        });
+
+        Ok(Peer {
+            addr: addr.to_string(),
+            ip: stream.peer_addr()?.ip(),
+            listen_addr: if inbound {
+                String::new()
+            } else {
+                addr.to_string()
+            },
+            inbound,
+            version: 0,
+            user_agent: String::new(),
+            services: 0,
+            best_height: -1,
+            last_seen: unix_time(),
+            version_received: false,
+            verack_received: false,
+            misbehavior: 0,
+            tx_window_start: 0,
+            tx_count: 0,
+            sender,
+            stream,
+        })
+    }
+
+    /// IsReady tells whether the version handshake is complete
+    pub fn is_ready(&self) -> bool {
+        self.version_received && self.verack_received
+    }
+
+    /// Send queues framed bytes for the writer thread
+    pub fn send(&self, data: Vec<u8>) -> Result<()> {
+        self.sender
+            .send(data)
+            .map_err(|_| format_err!("Connection to peer {} is closed", self.addr))
+    }
+
+    /// CountTx counts a relayed transaction and tells whether the peer is still within its rate
+    pub fn count_tx(&mut self) -> bool {
+        let now = unix_time();
+        if now.saturating_sub(self.tx_window_start) >= 60 {
+            self.tx_window_start = now;
+            self.tx_count = 0;
+        }
+        self.tx_count += 1;
+        self.tx_count <= MAX_TX_PER_MINUTE
+    }
+
+    /// Disconnect closes the connection, which also stops its reader and writer
+    pub fn disconnect(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// AddrInfo is what the peer store remembers about an address
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddrInfo {
+    pub addr: String,
+    pub last_seen: u64,
+    pub last_attempt: u64,
+    pub failures: u32,
+}
+
+/// PeerStore is the on-disk database of addresses we learned about
+#[derive(Clone)]
+pub struct PeerStore {
+    db: sled::Db,
+}
+
+impl PeerStore {
+    pub fn open(path: &str) -> Result<PeerStore> {
+        let db = sled::open(path)?;
+        Ok(PeerStore { db })
+    }
+
+    /// Add remembers a new address, known addresses are left untouched
+    pub fn add(&self, addr: &str) -> Result<()> {
+        if self.db.get(addr)?.is_none() {
+            info!("learn new address {}", addr);
+            let info = AddrInfo {
+                addr: addr.to_string(),
+                last_seen: 0,
+                last_attempt: 0,
+                failures: 0,
+            };
+            self.db.insert(addr, bincode::serialize(&info)?)?;
+        }
+        Ok(())
+    }
+
+    /// MarkAttempt records that we are dialing the address
+    pub fn mark_attempt(&self, addr: &str) -> Result<()> {
+        self.update(addr, |info| info.last_attempt = unix_time())
+    }
+
+    /// MarkGood records a completed handshake with the address
+    pub fn mark_good(&self, addr: &str) -> Result<()> {
+        self.update(addr, |info| {
+            info.last_seen = unix_time();
+            info.failures = 0;
+        })
+    }
+
+    /// MarkFailed records a failed connection attempt and forgets addresses that keep failing
+    pub fn mark_failed(&self, addr: &str) -> Result<()> {
+        self.update(addr, |info| info.failures += 1)?;
+        if let Some(info) = self.get(addr)? {
+            if info.failures >= MAX_FAILURES {
+                info!("forget address {} after {} failures", addr, info.failures);
+                self.db.remove(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// GetGoodAddrs returns the addresses we had a handshake with recently, most recent first
+    pub fn get_good_addrs(&self, limit: usize) -> Result<Vec<String>> {
+        let now = unix_time();
+        let mut infos = Vec::new();
+        for info in self.get_all()? {
+            if info.last_seen > 0 && now.saturating_sub(info.last_seen) < GOOD_PEER_AGE {
+                infos.push(info);
+            }
+        }
+        infos.sort_by_key(|info| Reverse(info.last_seen));
+        Ok(infos
+            .into_iter()
+            .take(limit)
+            .map(|info| info.addr)
+            .collect())
+    }
+
+    /// GetCandidates returns addresses worth dialing, known good ones first
+    pub fn get_candidates(&self) -> Result<Vec<String>> {
+        let now = unix_time();
+        let mut infos = Vec::new();
+        for info in self.get_all()? {
+            if info.failures == 0 || now.saturating_sub(info.last_attempt) >= RETRY_INTERVAL {
+                infos.push(info);
+            }
+        }
+        infos.sort_by(|a, b| {
+            b.last_seen
+                .cmp(&a.last_seen)
+                .then(a.failures.cmp(&b.failures))
+        });
+        Ok(infos.into_iter().map(|info| info.addr).collect())
+    }
+
+    fn get(&self, addr: &str) -> Result<Option<AddrInfo>> {
+        match self.db.get(addr)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_all(&self) -> Result<Vec<AddrInfo>> {
+        let mut infos = Vec::new();
+        for kv in self.db.iter() {
+            let (_, v) = kv?;
+            infos.push(bincode::deserialize(&v)?);
+        }
+        Ok(infos)
+    }
+
+    fn update<F: FnOnce(&mut AddrInfo)>(&self, addr: &str, f: F) -> Result<()> {
+        self.add(addr)?;
+        if let Some(mut info) = self.get(addr)? {
+            f(&mut info);
+            self.db.insert(addr, bincode::serialize(&info)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// BanList is the persisted list of banned ips and the time their ban ends.
+///
+/// The database is opened for every call, like the UTXO set, so that the
+/// command line can edit the list while a node is running.
+#[derive(Clone)]
+pub struct BanList {
+    path: String,
+}
+
+impl BanList {
+    pub fn new(path: &str) -> BanList {
+        BanList {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn ban(&self, addr: &str, until: u64) -> Result<()> {
+        let db = self.open()?;
+        db.insert(addr, &until.to_be_bytes())?;
+        db.flush()?;
+        Ok(())
+    }
+
+    /// Unban lifts a ban and tells whether the address was banned
+    pub fn unban(&self, addr: &str) -> Result<bool> {
+        let db = self.open()?;
+        let removed = db.remove(addr)?.is_some();
+        db.flush()?;
+        Ok(removed)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        let db = self.open()?;
+        db.clear()?;
+        db.flush()?;
+        Ok(())
+    }
+
+    pub fn is_banned(&self, addr: &str) -> Result<bool> {
+        Ok(self.get_banned()?.iter().any(|(a, _)| a == addr))
+    }
+
+    /// GetBanned returns the active bans and drops the expired ones
+    pub fn get_banned(&self) -> Result<Vec<(String, u64)>> {
+        let db = self.open()?;
+        let now = unix_time();
+        let mut banned = Vec::new();
+        for kv in db.iter() {
+            let (k, v) = kv?;
+            let addr = String::from_utf8(k.to_vec())?;
+            let until = match <[u8; 8]>::try_from(v.as_ref()) {
+                Ok(bytes) => u64::from_be_bytes(bytes),
+                Err(_) => 0,
+            };
+            if until <= now {
+                db.remove(&k)?;
+            } else {
+                banned.push((addr, until));
+            }
+        }
+        Ok(banned)
+    }
+
+    /// Open retries for a moment because a node or the command line may hold the database
+    fn open(&self) -> Result<sled::Db> {
+        let mut tries = 0;
+        loop {
+            match sled::open(&self.path) {
+                Ok(db) => return Ok(db),
+                Err(e) if tries < 20 => {
+                    info!("ban list is busy: {}", e);
+                    tries += 1;
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn open_store(name: &str) -> (TempDir, PeerStore) {
+        let dir = TempDir::new(name);
+        let store = PeerStore::open(&dir.path()).unwrap();
+        (dir, store)
+    }
+
+    fn set_info(store: &PeerStore, addr: &str, last_seen: u64, last_attempt: u64, failures: u32) {
+        store
+            .update(addr, |info| {
+                info.last_seen = last_seen;
+                info.last_attempt = last_attempt;
+                info.failures = failures;
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_candidates_order() {
+        let (_dir, store) = open_store("candidates");
+        let now = unix_time();
+        set_info(&store, "failed-long-ago:3000", 0, now - RETRY_INTERVAL, 1);
+        set_info(&store, "failed-just-now:3000", 0, now, 1);
+        set_info(&store, "seen-earlier:3000", now - 100, now - 100, 0);
+        store.add("new:3000").unwrap();
+        set_info(&store, "seen-now:3000", now, now, 0);
+
+        // known good addresses first, then the new ones, then the ones that failed
+        // and waited long enough to be dialed again
+        assert_eq!(
+            store.get_candidates().unwrap(),
+            vec![
+                "seen-now:3000",
+                "seen-earlier:3000",
+                "new:3000",
+                "failed-long-ago:3000"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forget_failing_addr() {
+        let (_dir, store) = open_store("failures");
+        store.add("flaky:3000").unwrap();
+        for _ in 0..MAX_FAILURES - 1 {
+            store.mark_failed("flaky:3000").unwrap();
+        }
+        store.mark_good("flaky:3000").unwrap();
+        assert_eq!(store.get("flaky:3000").unwrap().unwrap().failures, 0);
+
+        store.add("down:3000").unwrap();
+        for _ in 0..MAX_FAILURES - 1 {
+            store.mark_failed("down:3000").unwrap();
+        }
+        assert_eq!(
+            store.get("down:3000").unwrap().unwrap().failures,
+            MAX_FAILURES - 1
+        );
+        store.mark_failed("down:3000").unwrap();
+        assert!(store.get("down:3000").unwrap().is_none());
+        assert_eq!(store.get_candidates().unwrap(), vec!["flaky:3000"]);
+    }
+
+    #[test]
+    fn test_good_addrs() {
+        let (_dir, store) = open_store("good-addrs");
+        let now = unix_time();
+        store.add("never-seen:3000").unwrap();
+        set_info(&store, "stale:3000", now - GOOD_PEER_AGE, 0, 0);
+        set_info(&store, "seen-earlier:3000", now - 100, 0, 0);
+        set_info(&store, "seen-now:3000", now, 0, 0);
+        set_info(&store, "seen-before:3000", now - 200, 0, 0);
+
+        assert_eq!(
+            store.get_good_addrs(10).unwrap(),
+            vec!["seen-now:3000", "seen-earlier:3000", "seen-before:3000"]
+        );
+        assert_eq!(
+            store.get_good_addrs(2).unwrap(),
+            vec!["seen-now:3000", "seen-earlier:3000"]
+        );
+    }
+}
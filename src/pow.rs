@@ -0,0 +1,82 @@
+use failure::format_err;
+
+use crate::errors::Result;
+
+#[allow(clippy::all)]
+mod u256 {
+    uint::construct_uint! {
+        /// U256 is the 256-bit number a block hash is compared with
+        pub struct U256(4);
+    }
+}
+pub use u256::U256;
+
+/// CompactToTarget decodes the compact "bits" form of a target: the high byte is
+/// the size of the number in bytes and the low 3 bytes are its most significant bytes
+pub fn compact_to_target(bits: u32) -> Result<U256> {
+    let size = bits >> 24;
+    let mut word = bits & 0x007fffff;
+    if bits & 0x00800000 != 0 && word != 0 {
+        return Err(format_err!("Negative target bits {:#010x}", bits));
+    }
+    if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+        return Err(format_err!("Overflowing target bits {:#010x}", bits));
+    }
+    if size <= 3 {
+        word >>= 8 * (3 - size);
+        Ok(U256::from(word))
+    } else {
+        Ok(U256::from(word) << (8 * (size - 3)) as usize)
+    }
+}
+
+/// TargetToCompact encodes a target in the compact "bits" form, rounding it down
+pub fn target_to_compact(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut compact = if size <= 3 {
+        target.low_u64() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u64()
+    } as u32;
+    // the sign bit must stay clear
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size as u32) << 24
+}
+
+/// GetWork returns the expected number of hashes needed to find a hash below the target
+pub fn get_work(bits: u32) -> Result<U256> {
+    let target = compact_to_target(bits)?;
+    if target.is_zero() {
+        return Err(format_err!("Zero target bits {:#010x}", bits));
+    }
+    // 2^256 / (target + 1), computed without overflowing 256 bits
+    Ok((!target / (target + U256::one())) + U256::one())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_bits() {
+        for bits in [0x1f00ffff, 0x1d00ffff, 0x207fffff, 0x1b0404cb] {
+            assert_eq!(target_to_compact(compact_to_target(bits).unwrap()), bits);
+        }
+        assert_eq!(
+            compact_to_target(0x1f00ffff).unwrap(),
+            U256::from(0xffff) << 224
+        );
+        assert_eq!(compact_to_target(0x01003456).unwrap(), U256::zero());
+        assert_eq!(compact_to_target(0x02123456).unwrap(), U256::from(0x1234));
+        assert!(compact_to_target(0x04923456).is_err());
+        assert!(compact_to_target(0xff123456).is_err());
+
+        assert_eq!(get_work(0x1f00ffff).unwrap(), U256::from(0x10001));
+        assert_eq!(get_work(0x207fffff).unwrap(), U256::from(2));
+        // the hardest target does not fit in 128 bits
+        assert_eq!(get_work(0x03000001).unwrap(), U256::one() << 255);
+    }
+}
@@ -1,126 +1,1370 @@
 use std::{
-    collections::{HashMap, HashSet},
-    io::Read,
-    net::TcpStream,
-    sync::{Arc, Mutex},
+    cmp::Reverse,
+    collections::HashMap,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
+use crypto::{digest::Digest, sha2::Sha256};
 use failure::format_err;
-use log::info;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 
-use crate::errors::Result;
-use crate::{block::Block, transaction::Transaction, utxoset::UTXOSet};
+use crate::chainparams::params;
+use crate::errors::{Misbehavior, Result};
+use crate::peer::{
+    unix_time, BanList, Peer, PeerStore, BAN_THRESHOLD, DEFAULT_BAN_TIME, NODE_NETWORK,
+};
+use crate::timedata::{add_time_sample, now_millis};
+use crate::{
+    assembler::BlockAssembler, block::Block, mempool::Mempool, miner::Miner, orphan::OrphanPool,
+    transaction::Transaction, txs::UnspentOutput, utxoset::UTXOSet,
+};
 
-const KNOWN_NODE1: &str = "localhost:3000";
 const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+const VERSION: i32 = 2;
+/// MIN_PEER_VERSION is the oldest protocol version we still talk to,
+/// version 2 added the timestamp to the version message
+const MIN_PEER_VERSION: i32 = 2;
+const USER_AGENT: &str = concat!("/blockchain-rust:", env!("CARGO_PKG_VERSION"), "/");
+const MAX_BLOCKS_IN_INV: usize = 500;
+const MAX_ADDR_IN_MSG: usize = 1000;
+const MAX_INV_ITEMS: usize = 1000;
+/// MAX_UTXOS_IN_MSG caps the outputs a node lists for a wallet, enough to pay most amounts
+const MAX_UTXOS_IN_MSG: usize = 1000;
+/// MAX_OUTBOUND_PEERS caps the sessions we open ourselves, inbound ones are not counted
+const MAX_OUTBOUND_PEERS: usize = 8;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// REQUEST_TIMEOUT is how long a wallet waits for the answers of a node
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// DIAL_INTERVAL is how often the connection manager looks for new outbound peers
+const DIAL_INTERVAL: Duration = Duration::from_secs(5);
+/// BLOCK_DOWNLOAD_TIMEOUT is how many seconds a requested block may take before
+/// the download is restarted, from another peer if one can serve it
+const BLOCK_DOWNLOAD_TIMEOUT: u64 = 30;
+/// HEADER_LEN is magic + command + payload length + payload checksum
+const HEADER_LEN: usize = 4 + CMD_LEN + 4 + 4;
+/// MAX_PAYLOAD_LEN must leave room for a block of max_block_size in a block message
+const MAX_PAYLOAD_LEN: usize = 2 * 1024 * 1024;
 
 pub struct Server {
+    /// bind_address is the socket address the listener binds to
+    bind_address: String,
+    /// node_address is the address other nodes reach us at, empty when it is not advertised
     node_address: String,
     mining_address: String,
+    seeds: Vec<String>,
+    ban_list: BanList,
     inner: Arc<Mutex<ServerInner>>,
 }
 
 struct ServerInner {
-    known_nodes: HashSet<String>,
+    peers: HashMap<String, Peer>,
+    peer_store: PeerStore,
     utxo: UTXOSet,
     blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    /// sync_peer is the peer the blocks in transit are downloaded from
+    sync_peer: String,
+    /// transit_since is when the download last made progress, in seconds
+    transit_since: u64,
+    mempool: Mempool,
+    orphans: OrphanPool,
+    /// mining is the cancel flag of the block being mined, if any
+    mining: Option<Arc<AtomicBool>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Blockmsg {
-    add_from: String,
     block: Block,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetBlockmsg {
-    add_from: String,
+    locator: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetDatamsg {
-    add_from: String,
     kind: String,
     id: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Invmsg {
-    add_from: String,
     kind: String,
     items: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Txmsg {
-    add_from: String,
     transaction: Transaction,
 }
 
+/// GetUtxosmsg asks a node for the outputs a wallet can spend
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetUtxosmsg {
+    pub_key_hash: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Utxosmsg {
+    outputs: Vec<UnspentOutput>,
+}
+
+/// Versionmsg opens the handshake, add_from is the address the sender listens on
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Versionmsg {
     add_from: String,
     version: i32,
     best_height: i32,
+    user_agent: String,
+    services: u64,
+    /// timestamp is the time of the sender in milliseconds, used to adjust ours
+    timestamp: u128,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
     Addr(Vec<String>),
     Version(Versionmsg),
+    Verack,
     Tx(Txmsg),
     GetData(GetDatamsg),
     GetBlock(GetBlockmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    GetUtxos(GetUtxosmsg),
+    Utxos(Utxosmsg),
 }
 
 impl Server {
-    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
-        let mut node_set = HashSet::new();
-        node_set.insert(String::from(KNOWN_NODE1));
+    pub fn new(
+        bind_address: &str,
+        node_address: &str,
+        miner_address: &str,
+        seeds: Vec<String>,
+        utxo: UTXOSet,
+    ) -> Result<Server> {
         Ok(Server {
-            node_address: String::from("localhost") + port,
+            bind_address: bind_address.to_string(),
+            node_address: node_address.to_string(),
             mining_address: miner_address.to_string(),
+            seeds,
+            ban_list: BanList::new(&utxo.blockchain.path("banlist")),
             inner: Arc::new(Mutex::new(ServerInner {
-                known_nodes: node_set,
+                peers: HashMap::new(),
+                peer_store: PeerStore::open(&utxo.blockchain.path("peers"))?,
                 utxo,
                 blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                sync_peer: String::new(),
+                transit_since: unix_time(),
+                mempool: Mempool::new(),
+                orphans: OrphanPool::new(),
+                mining: None,
             })),
         })
     }
 
-    fn handle_connnection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
+    /// StartServer binds the listener and serves peers until the process is stopped
+    pub fn start_server(&self) -> Result<()> {
+        info!(
+            "Start server at {}, advertised as {:?}, minning address: {}",
+            &self.bind_address, &self.node_address, &self.mining_address
+        );
+        let listener = TcpListener::bind(&self.bind_address)?;
+        info!("Server listen...");
+
+        let peer_store = self.get_peer_store();
+        for seed in &self.seeds {
+            if *seed != self.node_address {
+                peer_store.add(seed)?;
+            }
+        }
+        let server1 = self.clone_handle();
+        thread::spawn(move || server1.maintain_connections());
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server1 = self.clone_handle();
+            thread::spawn(move || server1.handle_connnection(stream, None));
+        }
+
+        Ok(())
+    }
+
+    /// SendTransaction submits a transaction to a running node
+    pub fn send_transaction(addr: &str, tx: &Transaction) -> Result<()> {
+        let mut stream = Self::connect_node(addr)?;
+        let data = Txmsg {
+            transaction: tx.clone(),
+        };
+        stream.write_all(&encode_message("tx", &data)?)?;
+        Ok(())
+    }
+
+    /// GetSpendableOutputs asks a running node for the outputs of a public key hash
+    /// that are neither spent by its chain nor by its mempool
+    pub fn get_spendable_outputs(addr: &str, pub_key_hash: &[u8]) -> Result<Vec<UnspentOutput>> {
+        let mut stream = Self::connect_node(addr)?;
+        let data = GetUtxosmsg {
+            pub_key_hash: pub_key_hash.to_vec(),
+        };
+        stream.write_all(&encode_message("getutxos", &data)?)?;
+        loop {
+            match read_message(&mut stream)? {
+                Some(Message::Utxos(msg)) => return Ok(msg.outputs),
+                Some(_) => {}
+                None => return Err(format_err!("Node {} closed the connection", addr)),
+            }
+        }
+    }
+
+    /// ConnectNode opens a session with a running node as a wallet, which serves nothing
+    fn connect_node(addr: &str) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let version = Versionmsg {
+            add_from: String::new(),
+            version: VERSION,
+            best_height: -1,
+            user_agent: String::from(USER_AGENT),
+            services: 0,
+            timestamp: now_millis(),
+        };
+        stream.write_all(&encode_message("version", &version)?)?;
+        loop {
+            match read_message(&mut stream)? {
+                Some(Message::Version(msg)) if msg.version < MIN_PEER_VERSION => {
+                    return Err(format_err!(
+                        "Node {} has an old version {}",
+                        addr,
+                        msg.version
+                    ))
+                }
+                Some(Message::Verack) => break,
+                Some(_) => {}
+                None => return Err(format_err!("Node {} closed the connection", addr)),
+            }
+        }
+        stream.write_all(&encode_message("verack", &())?)?;
+        Ok(stream)
+    }
+
+    fn clone_handle(&self) -> Server {
+        Server {
+            bind_address: self.bind_address.clone(),
+            node_address: self.node_address.clone(),
+            mining_address: self.mining_address.clone(),
+            seeds: self.seeds.clone(),
+            ban_list: self.ban_list.clone(),
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /* ------------------- inner halp functions ----------------------------------*/
+
+    /// MaintainConnections keeps dialing addresses from the peer store
+    /// until MAX_OUTBOUND_PEERS outbound sessions are open
+    fn maintain_connections(&self) {
+        loop {
+            if let Err(e) = self.disconnect_banned_peers() {
+                error!("failed to check the ban list: {}", e);
+            }
+            if let Err(e) = self.open_outbound_connections() {
+                error!("failed to open outbound connections: {}", e);
+            }
+            if let Err(e) = self.check_block_download() {
+                error!("failed to check the block download: {}", e);
+            }
+            thread::sleep(DIAL_INTERVAL);
+        }
+    }
+
+    /// CheckBlockDownload restarts a block download that made no progress for
+    /// BLOCK_DOWNLOAD_TIMEOUT, so that a peer holding back a block cannot stall us
+    fn check_block_download(&self) -> Result<()> {
+        let (sync_peer, stalled_block) = {
+            let inner = self.inner.lock().unwrap();
+            if unix_time().saturating_sub(inner.transit_since) < BLOCK_DOWNLOAD_TIMEOUT {
+                return Ok(());
+            }
+            (
+                inner.sync_peer.clone(),
+                inner.blocks_in_transit.first().cloned(),
+            )
+        };
+        if let Some(block_hash) = stalled_block {
+            info!(
+                "block {} requested from {} did not arrive in time",
+                block_hash, sync_peer
+            );
+        }
+        // the peers ahead of us can serve the download, the stalling one comes last
+        let best_height = self.get_best_height()?;
+        let mut peers = self.get_peers_ahead(best_height);
+        peers.sort_by_key(|(addr, height)| (*addr == sync_peer, Reverse(*height)));
+        self.replace_in_transit("", Vec::new());
+        if let Some((addr, _)) = peers.first() {
+            info!("download the blocks from {}", addr);
+            self.send_get_blocks(addr)?;
+        }
+        Ok(())
+    }
+
+    /// GetPeersAhead returns the ready peers that serve the chain and told a
+    /// height above the given one, with that height
+    fn get_peers_ahead(&self, height: i32) -> Vec<(String, i32)> {
+        let inner = self.inner.lock().unwrap();
+        let mut peers = Vec::new();
+        for (addr, peer) in &inner.peers {
+            if peer.is_ready() && peer.services & NODE_NETWORK != 0 && peer.best_height > height {
+                peers.push((addr.clone(), peer.best_height));
+            }
+        }
+        peers
+    }
+
+    /// DisconnectBannedPeers drops the sessions of peers banned from the command line
+    fn disconnect_banned_peers(&self) -> Result<()> {
+        let banned = self.ban_list.get_banned()?;
+        let peers: Vec<(String, String)> = {
+            let inner = self.inner.lock().unwrap();
+            let peers = inner.peers.values();
+            peers.map(|p| (p.addr.clone(), p.ip.to_string())).collect()
+        };
+        for (addr, ip) in peers {
+            if banned.iter().any(|(a, _)| *a == ip) {
+                info!("peer {} is banned", addr);
+                self.remove_peer(&addr);
+            }
+        }
+        Ok(())
+    }
+
+    fn open_outbound_connections(&self) -> Result<()> {
+        let mut missing = MAX_OUTBOUND_PEERS.saturating_sub(self.count_outbound_peers());
+        if missing == 0 {
+            return Ok(());
+        }
+
+        let peer_store = self.get_peer_store();
+        let mut candidates = peer_store.get_candidates()?;
+        if candidates.is_empty() && self.get_ready_peers().is_empty() {
+            candidates.clone_from(&self.seeds);
+        }
+        for addr in candidates {
+            if missing == 0 {
+                break;
+            }
+            if addr == self.node_address || self.peer_is_known(&addr) {
+                continue;
+            }
+            peer_store.mark_attempt(&addr)?;
+            match self.connect_peer(&addr) {
+                Ok(()) => missing -= 1,
+                Err(e) => {
+                    info!("failed to connect to {}: {}", addr, e);
+                    peer_store.mark_failed(&addr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// ConnectPeer opens an outbound session and starts the handshake
+    fn connect_peer(&self, addr: &str) -> Result<()> {
+        if addr == self.node_address || self.peer_is_known(addr) {
+            return Ok(());
+        }
+        let sock_addr = match addr.to_socket_addrs()?.next() {
+            Some(a) => a,
+            None => return Err(format_err!("Can not resolve {}", addr)),
+        };
+        if self.ban_list.is_banned(&sock_addr.ip().to_string())? {
+            return Err(format_err!("Peer {} is banned", addr));
+        }
+        info!("connect to peer {}", addr);
+        let stream = TcpStream::connect_timeout(&sock_addr, CONNECT_TIMEOUT)?;
+        let peer = Peer::new(addr, stream.try_clone()?, false)?;
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .insert(addr.to_string(), peer);
+
+        let server1 = self.clone_handle();
+        let peer_addr = addr.to_string();
+        thread::spawn(move || server1.handle_connnection(stream, Some(peer_addr)));
+        self.send_version(addr)
+    }
+
+    /// AcceptPeer registers an inbound session once its version message arrived,
+    /// the session is known by its socket address whatever the peer claims to be
+    fn accept_peer(&self, stream: &TcpStream, msg: &Versionmsg) -> Result<String> {
+        let sock_addr = stream.peer_addr()?;
+        if !msg.add_from.is_empty() && msg.add_from == self.node_address {
+            return Err(format_err!("Connected to ourselves"));
+        }
+        if self.ban_list.is_banned(&sock_addr.ip().to_string())? {
+            return Err(format_err!("Peer {} is banned", sock_addr));
+        }
+        let addr = sock_addr.to_string();
+        let mut peer = Peer::new(&addr, stream.try_clone()?, true)?;
+        peer.listen_addr.clone_from(&msg.add_from);
+        self.inner.lock().unwrap().peers.insert(addr.clone(), peer);
+        Ok(addr)
+    }
+
+    fn remove_peer(&self, addr: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(peer) = inner.peers.remove(addr) {
+            info!("disconnect peer {}", addr);
+            peer.disconnect();
+        }
+        if inner.sync_peer == addr {
+            inner.sync_peer.clear();
+            inner.blocks_in_transit.clear();
+        }
+    }
+
+    fn get_peer_store(&self) -> PeerStore {
+        self.inner.lock().unwrap().peer_store.clone()
+    }
+
+    fn count_outbound_peers(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.values().filter(|peer| !peer.inbound).count()
+    }
+
+    /// GetNetworkPeers returns the listening addresses of the ready peers that serve the chain
+    fn get_network_peers(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut peers = Vec::new();
+        for peer in inner.peers.values() {
+            if peer.is_ready() && peer.services & NODE_NETWORK != 0 && !peer.listen_addr.is_empty()
+            {
+                peers.push(peer.listen_addr.clone());
+            }
+        }
+        peers
+    }
+
+    /// PeerIsKnown tells whether we have a session with the address or with a peer listening on it
+    fn peer_is_known(&self, addr: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.contains_key(addr) || inner.peers.values().any(|p| p.listen_addr == addr)
+    }
+
+    fn peer_is_ready(&self, addr: &str) -> bool {
+        match self.inner.lock().unwrap().peers.get(addr) {
+            Some(peer) => peer.is_ready(),
+            None => false,
+        }
+    }
+
+    /// GetReadyPeers returns the peers that completed the handshake
+    fn get_ready_peers(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut peers = Vec::new();
+        for (addr, peer) in &inner.peers {
+            if peer.is_ready() {
+                peers.push(addr.clone());
+            }
+        }
+        peers
+    }
+
+    fn update_peer<F: FnOnce(&mut Peer)>(&self, addr: &str, f: F) {
+        if let Some(peer) = self.inner.lock().unwrap().peers.get_mut(addr) {
+            f(peer);
+        }
+    }
+
+    /// Misbehaving raises the misbehavior score of a peer, banning its ip at BAN_THRESHOLD
+    fn misbehaving(&self, addr: &str, score: u32, reason: &str) {
+        let mut total = 0;
+        let mut ip = None;
+        self.update_peer(addr, |peer| {
+            peer.misbehavior += score;
+            total = peer.misbehavior;
+            ip = Some(peer.ip);
+        });
+        info!(
+            "peer {} misbehaving (+{} = {}): {}",
+            addr, score, total, reason
+        );
+        if total >= BAN_THRESHOLD {
+            if let Some(ip) = ip {
+                info!(
+                    "ban peer {} at {} for {} seconds",
+                    addr, ip, DEFAULT_BAN_TIME
+                );
+                if let Err(e) = self
+                    .ban_list
+                    .ban(&ip.to_string(), unix_time() + DEFAULT_BAN_TIME)
+                {
+                    error!("failed to ban peer {}: {}", addr, e);
+                }
+            }
+            self.remove_peer(addr);
+        }
+    }
+
+    /// HandleError scores the protocol violations of a peer and logs the other errors
+    fn handle_error(&self, addr: &str, e: &failure::Error) {
+        match e.downcast_ref::<Misbehavior>() {
+            Some(m) => self.misbehaving(addr, m.score, &m.reason),
+            None => error!("failed to handle message from {}: {}", addr, e),
+        }
+    }
+
+    fn replace_in_transit(&self, sync_peer: &str, hashs: Vec<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sync_peer = if hashs.is_empty() {
+            String::new()
+        } else {
+            sync_peer.to_string()
+        };
+        inner.blocks_in_transit = hashs;
+        inner.transit_since = unix_time();
+    }
+
+    fn get_in_transit(&self) -> Vec<String> {
+        self.inner.lock().unwrap().blocks_in_transit.clone()
+    }
+
+    fn get_mempool_tx(&self, txid: &str) -> Option<Transaction> {
+        self.inner.lock().unwrap().mempool.get(txid).cloned()
+    }
+
+    fn insert_mempool(&self, tx: Transaction) -> Result<()> {
+        let inner = &mut *self.inner.lock().unwrap();
+        inner.mempool.add(tx, &inner.utxo)
+    }
+
+    fn get_best_height(&self) -> Result<i32> {
+        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+    }
+
+    fn get_block_locator(&self) -> Result<Vec<String>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_block_locator()
+    }
+
+    fn get_block_hashes_after(&self, locator: &[String]) -> Result<Vec<String>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_block_hashes_after(locator, MAX_BLOCKS_IN_INV)
+    }
+
+    fn get_tip_hash(&self) -> String {
+        self.inner.lock().unwrap().utxo.blockchain.get_tip_hash()
+    }
+
+    fn has_block(&self, block_hash: &str) -> bool {
+        self.get_block(block_hash).is_ok()
+    }
+
+    fn is_orphan(&self, block_hash: &str) -> bool {
+        self.inner.lock().unwrap().orphans.contains(block_hash)
+    }
+
+    fn add_orphan(&self, block: Block, from: &str) {
+        self.inner.lock().unwrap().orphans.add(block, from)
+    }
+
+    fn take_orphans(&self, parent_hash: &str) -> Vec<(Block, String)> {
+        self.inner
+            .lock()
+            .unwrap()
+            .orphans
+            .take_children(parent_hash)
+    }
+
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_block(block_hash)
+    }
+
+    /// ConnectBlock validates and stores a block, switching to its branch when
+    /// it has more work, and tells whether the block became the new tip
+    fn connect_block(&self, block: Block) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.utxo.blockchain.check_block(&block)?;
+        let old_tip = inner.utxo.blockchain.get_tip_hash();
+        let extends_tip = block.get_prev_hash() == old_tip;
+        if extends_tip {
+            inner.utxo.validate_block(&block)?;
+        }
+        inner.utxo.blockchain.add_block(block.clone())?;
+        let new_tip = inner.utxo.blockchain.get_tip_hash();
+        if new_tip == old_tip {
+            info!("block {} is stored on a side branch", block.get_hash());
+            return Ok(false);
+        }
+
+        if old_tip.is_empty() {
+            inner.utxo.reindex()?;
+        } else if extends_tip {
+            inner.utxo.update(&block)?;
+        } else {
+            reorganize(&mut inner, &old_tip, &new_tip)?;
+            return Ok(true);
+        }
+        inner.mempool.remove_block_transactions(&block);
+        Ok(true)
+    }
+
+    /// ConnectOrphans connects the orphans waiting for a block that was just
+    /// connected, then the orphans waiting for those
+    fn connect_orphans(&self, parent_hash: &str) {
+        let mut parents = vec![parent_hash.to_string()];
+        while let Some(parent) = parents.pop() {
+            for (block, from) in self.take_orphans(&parent) {
+                let hash = block.get_hash();
+                match self.connect_block(block) {
+                    Ok(new_tip) => {
+                        info!("connect orphan block {}", hash);
+                        if new_tip {
+                            self.broadcast_inv(&from, "block", &hash);
+                        }
+                        parents.push(hash);
+                    }
+                    Err(e) => self.handle_error(&from, &e),
+                }
+            }
+        }
+    }
+
+    /// StartMining cancels the block being mined and, when this node mines and
+    /// the mempool is not empty, starts mining a new template in the background
+    fn start_mining(&self) -> Result<()> {
+        if self.mining_address.is_empty() {
+            return Ok(());
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        let assembler = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(cancel) = inner.mining.take() {
+                cancel.store(true, Ordering::Relaxed);
+            }
+            if inner.mempool.is_empty() {
+                return Ok(());
+            }
+            // a newer call cancels this template even while it is being built
+            inner.mining = Some(Arc::clone(&cancel));
+            BlockAssembler::new(&inner.utxo.blockchain, &inner.mempool, &self.mining_address)?
+        };
+        let template = assembler.create_new_block()?;
+
+        let server = self.clone_handle();
+        thread::spawn(
+            move || match Miner::new(Miner::default_threads()).mine(template, &cancel) {
+                Ok(Some(block)) => server.block_mined(block),
+                Ok(None) => {}
+                Err(e) => error!("mining failed: {}", e),
+            },
+        );
+        Ok(())
+    }
+
+    /// BlockMined connects a block found by the miner, announces it and mines the
+    /// transactions left in the mempool
+    fn block_mined(&self, block: Block) {
+        let block_hash = block.get_hash();
+        match self.connect_block(block) {
+            Ok(true) => {
+                info!("mined block {}", block_hash);
+                self.broadcast_inv("", "block", &block_hash);
+            }
+            Ok(false) => info!("mined block {} is stale", block_hash),
+            Err(e) => error!("mined block {} is invalid: {}", block_hash, e),
+        }
+        if let Err(e) = self.start_mining() {
+            error!("failed to start mining: {}", e);
+        }
+    }
+
+    /* -----------------------------------------------------*/
+
+    fn send_data(&self, addr: &str, data: Vec<u8>) -> Result<()> {
+        match self.inner.lock().unwrap().peers.get(addr) {
+            Some(peer) => peer.send(data),
+            None => {
+                info!("peer {} is not connected, drop the message", addr);
+                Ok(())
+            }
+        }
+    }
+
+    fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
+        info!("send block data to: {} block hash: {}", addr, b.get_hash());
+        let data = Blockmsg { block: b.clone() };
+        let data = encode_message("block", &data)?;
+        self.send_data(addr, data)
+    }
+
+    /// SendAddr gossips our own address when we advertise one, the connected nodes and the good addresses of the store
+    fn send_addr(&self, addr: &str) -> Result<()> {
+        info!("send address info to: {}", addr);
+        let mut nodes = Vec::new();
+        if !self.node_address.is_empty() {
+            nodes.push(self.node_address.clone());
+        }
+        let known = self.get_network_peers();
+        let good = self.get_peer_store().get_good_addrs(MAX_ADDR_IN_MSG)?;
+        for node in known.into_iter().chain(good) {
+            if node != addr && !nodes.contains(&node) && nodes.len() < MAX_ADDR_IN_MSG {
+                nodes.push(node);
+            }
+        }
+        let data = encode_message("addr", &nodes)?;
+        self.send_data(addr, data)
+    }
+
+    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
+        info!(
+            "send inv message to: {} kind: {} data: {:?}",
+            addr, kind, items
+        );
+        let data = Invmsg {
+            kind: kind.to_string(),
+            items,
+        };
+        let data = encode_message("inv", &data)?;
+        self.send_data(addr, data)
+    }
+
+    fn send_get_blocks(&self, addr: &str) -> Result<()> {
+        info!("send get blocks message to: {}", addr);
+        let data = GetBlockmsg {
+            locator: self.get_block_locator()?,
+        };
+        let data = encode_message("getblocks", &data)?;
+        self.send_data(addr, data)
+    }
+
+    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+        info!(
+            "send get data message to: {} kind: {} id: {}",
+            addr, kind, id
+        );
+        let data = GetDatamsg {
+            kind: kind.to_string(),
+            id: id.to_string(),
+        };
+        let data = encode_message("getdata", &data)?;
+        self.send_data(addr, data)
+    }
+
+    fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
+        info!("send tx to: {} txid: {}", addr, &tx.id);
+        let data = Txmsg {
+            transaction: tx.clone(),
+        };
+        let data = encode_message("tx", &data)?;
+        self.send_data(addr, data)
+    }
+
+    fn send_version(&self, addr: &str) -> Result<()> {
+        info!("send version info to: {}", addr);
+        let data = Versionmsg {
+            add_from: self.node_address.clone(),
+            best_height: self.get_best_height()?,
+            version: VERSION,
+            user_agent: String::from(USER_AGENT),
+            services: NODE_NETWORK,
+            timestamp: now_millis(),
+        };
+        let data = encode_message("version", &data)?;
+        self.send_data(addr, data)
+    }
+
+    fn send_verack(&self, addr: &str) -> Result<()> {
+        info!("send verack to: {}", addr);
+        let data = encode_message("verack", &())?;
+        self.send_data(addr, data)
+    }
+
+    /// Broadcast announces an inventory item to every ready peer except the one it came from
+    fn broadcast_inv(&self, from: &str, kind: &str, id: &str) {
+        for node in self.get_ready_peers() {
+            if node != from {
+                if let Err(e) = self.send_inv(&node, kind, vec![id.to_string()]) {
+                    error!("failed to send inv to {}: {}", node, e);
+                }
+            }
+        }
+    }
+
+    fn handle_version(&self, addr: &str, msg: Versionmsg) -> Result<()> {
+        info!("receive version msg from {}: {:#?}", addr, msg);
+        if msg.version < MIN_PEER_VERSION {
+            return Err(format_err!(
+                "Peer {} uses an incompatible version {}",
+                addr,
+                msg.version
+            ));
+        }
+
+        let mut inbound = false;
+        let mut duplicate = false;
+        let mut verack_received = false;
+        let mut ip = None;
+        self.update_peer(addr, |peer| {
+            duplicate = peer.version_received;
+            ip = Some(peer.ip);
+            peer.version = msg.version;
+            peer.user_agent = msg.user_agent.clone();
+            peer.services = msg.services;
+            peer.best_height = msg.best_height;
+            peer.version_received = true;
+            inbound = peer.inbound;
+            verack_received = peer.verack_received;
+        });
+        if duplicate {
+            return Err(Misbehavior::error(
+                10,
+                String::from("duplicate version message"),
+            ));
+        }
+        // a wallet submitting a transaction does not vote on our clock
+        if msg.services & NODE_NETWORK != 0 {
+            if let Some(ip) = ip {
+                add_time_sample(ip, msg.timestamp);
+            }
+        }
+
+        if inbound {
+            self.send_version(addr)?;
+        }
+        self.send_verack(addr)?;
+        if verack_received {
+            self.handle_handshake_done(addr)?;
+        }
+        Ok(())
+    }
+
+    fn handle_verack(&self, addr: &str) -> Result<()> {
+        info!("receive verack msg from {}", addr);
+        let mut version_received = false;
+        self.update_peer(addr, |peer| {
+            peer.verack_received = true;
+            version_received = peer.version_received;
+        });
+        if version_received {
+            self.handle_handshake_done(addr)?;
+        }
+        Ok(())
+    }
+
+    fn handle_handshake_done(&self, addr: &str) -> Result<()> {
+        let mut peer_height = -1;
+        let mut services = 0;
+        let mut inbound = false;
+        let mut listen_addr = String::new();
+        self.update_peer(addr, |peer| {
+            peer_height = peer.best_height;
+            services = peer.services;
+            inbound = peer.inbound;
+            listen_addr.clone_from(&peer.listen_addr);
+            info!(
+                "handshake done with {} {} inbound: {} height: {}",
+                peer.addr, peer.user_agent, peer.inbound, peer.best_height
+            );
+        });
+
+        if services & NODE_NETWORK == 0 {
+            return Ok(());
+        }
+        let peer_store = self.get_peer_store();
+        if inbound {
+            // an inbound peer is only worth gossiping if it told where it listens
+            if !listen_addr.is_empty() {
+                peer_store.add(&listen_addr)?;
+                let data = encode_message("addr", &vec![listen_addr])?;
+                for node in self.get_ready_peers() {
+                    if node != addr {
+                        if let Err(e) = self.send_data(&node, data.clone()) {
+                            error!("failed to send addr to {}: {}", node, e);
+                        }
+                    }
+                }
+            }
+        } else {
+            peer_store.mark_good(addr)?;
+        }
+
+        if self.get_best_height()? < peer_height && self.get_in_transit().is_empty() {
+            self.send_get_blocks(addr)?;
+        }
+        self.send_addr(addr)
+    }
+
+    fn handle_addr(&self, addr: &str, msg: Vec<String>) -> Result<()> {
+        info!("receive address msg from {}: {:#?}", addr, msg);
+        if msg.len() > MAX_ADDR_IN_MSG {
+            return Err(Misbehavior::error(
+                20,
+                format!("addr message with {} addresses", msg.len()),
+            ));
+        }
+        let peer_store = self.get_peer_store();
+        for node in msg {
+            if node != self.node_address {
+                peer_store.add(&node)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_block(&self, addr: &str, msg: Blockmsg) -> Result<()> {
+        info!("receive block msg: {}, {}", addr, msg.block.get_hash());
+        let block_hash = msg.block.get_hash();
+        let height = msg.block.get_height() as i32;
+        self.update_peer(addr, |peer| {
+            peer.best_height = peer.best_height.max(height);
+        });
+        if self.has_block(&block_hash) || self.is_orphan(&block_hash) {
+            return Ok(());
+        }
+
+        let mut in_transit = self.get_in_transit();
+        let requested = in_transit.first() == Some(&block_hash);
+        let prev_hash = msg.block.get_prev_hash();
+        if !prev_hash.is_empty() && !self.has_block(&prev_hash) {
+            if !msg.block.check_proof_of_work()? {
+                return Err(Misbehavior::error(
+                    100,
+                    format!("Block {} has an invalid proof of work", block_hash),
+                ));
+            }
+            info!(
+                "block {} has an unknown parent, asking for its ancestors",
+                block_hash
+            );
+            self.add_orphan(msg.block, addr);
+            if requested {
+                self.replace_in_transit(addr, Vec::new());
+            }
+            return self.send_get_blocks(addr);
+        }
+        let old_tip = self.get_tip_hash();
+        let new_tip = match self.connect_block(msg.block) {
+            Ok(new_tip) => new_tip,
+            Err(e) => {
+                self.replace_in_transit(addr, Vec::new());
+                return Err(e);
+            }
+        };
+        self.connect_orphans(&block_hash);
+        if self.get_tip_hash() != old_tip {
+            self.start_mining()?;
+        }
+
+        if !requested {
+            if new_tip {
+                self.broadcast_inv(addr, "block", &block_hash);
+            }
+            return Ok(());
+        }
+
+        in_transit.remove(0);
+        match in_transit.first() {
+            Some(next) => self.send_get_data(addr, "block", next)?,
+            None => self.send_get_blocks(addr)?,
+        }
+        self.replace_in_transit(addr, in_transit);
+        Ok(())
+    }
+
+    fn handle_inv(&self, addr: &str, msg: Invmsg) -> Result<()> {
+        info!("receive inv msg from {}: {:#?}", addr, msg);
+        if msg.items.len() > MAX_INV_ITEMS {
+            return Err(Misbehavior::error(
+                20,
+                format!("inv message with {} items", msg.items.len()),
+            ));
+        }
+        if msg.kind == "block" {
+            if !self.get_in_transit().is_empty() {
+                info!("block download in progress, ignore the inventory");
+                return Ok(());
+            }
+            let mut new_in_transit = Vec::new();
+            for b in &msg.items {
+                if !self.has_block(b) && !self.is_orphan(b) {
+                    new_in_transit.push(b.clone());
+                }
+            }
+            if let Some(block_hash) = new_in_transit.first() {
+                self.send_get_data(addr, "block", block_hash)?;
+            }
+            self.replace_in_transit(addr, new_in_transit);
+        } else if msg.kind == "tx" {
+            for txid in &msg.items {
+                if self.get_mempool_tx(txid).is_none() {
+                    self.send_get_data(addr, "tx", txid)?;
+                }
+            }
+        } else {
+            return Err(Misbehavior::error(
+                10,
+                format!("unknown inv kind {}", msg.kind),
+            ));
+        }
+        Ok(())
+    }
+
+    fn handle_get_blocks(&self, addr: &str, msg: GetBlockmsg) -> Result<()> {
+        info!("receive get blocks msg from {}: {:#?}", addr, msg);
+        let block_hashs = self.get_block_hashes_after(&msg.locator)?;
+        if !block_hashs.is_empty() {
+            self.send_inv(addr, "block", block_hashs)?;
+        }
+        Ok(())
+    }
+
+    fn handle_get_data(&self, addr: &str, msg: GetDatamsg) -> Result<()> {
+        info!("receive get data msg from {}: {:#?}", addr, msg);
+        if msg.kind == "block" {
+            let block = self.get_block(&msg.id)?;
+            self.send_block(addr, &block)?;
+        } else if msg.kind == "tx" {
+            match self.get_mempool_tx(&msg.id) {
+                Some(tx) => self.send_tx(addr, &tx)?,
+                None => return Err(format_err!("tx {} is not in the mempool", msg.id)),
+            }
+        } else {
+            return Err(Misbehavior::error(
+                10,
+                format!("unknown getdata kind {}", msg.kind),
+            ));
+        }
+        Ok(())
+    }
+
+    /// HandleGetUtxos lists the mature outputs of a wallet that the mempool does not spend yet
+    fn handle_get_utxos(&self, addr: &str, msg: GetUtxosmsg) -> Result<()> {
+        info!("receive get utxos msg from {}", addr);
+        let outputs: Vec<UnspentOutput> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .utxo
+                .get_spendable_outputs(&msg.pub_key_hash)?
+                .into_iter()
+                .filter(|utxo| !inner.mempool.is_spent(&utxo.txid, utxo.vout))
+                .take(MAX_UTXOS_IN_MSG)
+                .collect()
+        };
+        let data = encode_message("utxos", &Utxosmsg { outputs })?;
+        self.send_data(addr, data)
+    }
+
+    fn handle_tx(&self, addr: &str, msg: Txmsg) -> Result<()> {
+        info!("receive tx msg: {} {}", addr, &msg.transaction.id);
+        let mut within_rate = true;
+        self.update_peer(addr, |peer| within_rate = peer.count_tx());
+        if !within_rate {
+            return Err(Misbehavior::error(5, String::from("too many transactions")));
+        }
+        if self.get_mempool_tx(&msg.transaction.id).is_some() {
+            return Ok(());
+        }
+        self.insert_mempool(msg.transaction.clone())?;
+        self.broadcast_inv(addr, "tx", &msg.transaction.id);
+        self.start_mining()
+    }
+
+    /// HandleConnection serves one peer session until it is closed.
+    /// Inbound sessions are registered once the peer's version message arrives.
+    fn handle_connnection(&self, mut stream: TcpStream, outbound: Option<String>) {
+        let mut addr = outbound;
+        if let Err(e) = self.read_messages(&mut stream, &mut addr) {
+            error!("peer connection error: {}", e);
+        }
+        match addr {
+            Some(addr) => self.remove_peer(&addr),
+            None => {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+    fn read_messages(&self, stream: &mut TcpStream, addr: &mut Option<String>) -> Result<()> {
+        info!("Accept connection from {}", stream.peer_addr()?);
+        loop {
+            let (header, payload) = match read_frame(stream) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    if let Some(peer) = addr {
+                        self.handle_error(peer, &e);
+                    }
+                    return Err(e);
+                }
+            };
+            // the whole frame was read, so a bad payload does not break the stream
+            let cmd = match (decode_frame(&header, &payload), addr.as_ref()) {
+                (Ok(cmd), _) => cmd,
+                (Err(e), Some(peer)) => {
+                    self.handle_error(peer, &e);
+                    continue;
+                }
+                (Err(e), None) => return Err(e),
+            };
 
-        match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
-            Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
+            let peer = match (addr.clone(), &cmd) {
+                (Some(peer), _) => peer,
+                (None, Message::Version(msg)) => {
+                    let peer = self.accept_peer(stream, msg)?;
+                    *addr = Some(peer.clone());
+                    peer
+                }
+                (None, _) => {
+                    return Err(format_err!(
+                        "{} sent a message before version",
+                        stream.peer_addr()?
+                    ))
+                }
+            };
+            self.update_peer(&peer, |p| p.last_seen = unix_time());
+
+            let handshake = matches!(cmd, Message::Version(_) | Message::Verack);
+            if !handshake && !self.peer_is_ready(&peer) {
+                self.misbehaving(&peer, 10, "message before the handshake");
+                return Err(format_err!("{} sent a message before the handshake", peer));
+            }
+            let result = match cmd {
+                Message::Version(data) => self.handle_version(&peer, data),
+                Message::Verack => self.handle_verack(&peer),
+                Message::Addr(data) => self.handle_addr(&peer, data),
+                Message::Block(data) => self.handle_block(&peer, data),
+                Message::Inv(data) => self.handle_inv(&peer, data),
+                Message::GetBlock(data) => self.handle_get_blocks(&peer, data),
+                Message::GetData(data) => self.handle_get_data(&peer, data),
+                Message::Tx(data) => self.handle_tx(&peer, data),
+                Message::GetUtxos(data) => self.handle_get_utxos(&peer, data),
+                Message::Utxos(_) => Err(Misbehavior::error(
+                    10,
+                    String::from("unsolicited utxos message"),
+                )),
+            };
+            if let Err(e) = result {
+                self.handle_error(&peer, &e);
+                if handshake && e.downcast_ref::<Misbehavior>().is_none() {
+                    return Err(e);
+                }
+            }
         }
     }
 }
 
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
+/// Reorganize moves the UTXO set and the mempool from the old tip to a heavier branch,
+/// going back to the old tip if the new branch has an invalid block
+fn reorganize(inner: &mut ServerInner, old_tip: &str, new_tip: &str) -> Result<()> {
+    let (disconnect, connect) = inner.utxo.blockchain.get_reorg_path(old_tip, new_tip)?;
+    info!(
+        "reorganize from {} to {}: disconnect {} blocks, connect {} blocks",
+        old_tip,
+        new_tip,
+        disconnect.len(),
+        connect.len()
+    );
+    if let Err(e) = move_utxo_set(&inner.utxo, &disconnect, &connect) {
+        info!(
+            "can not move to the new branch, go back to {}: {}",
+            old_tip, e
+        );
+        inner.utxo.blockchain.set_tip(old_tip)?;
+        return Err(e);
+    }
+
+    // transactions of the disconnected blocks go back to the mempool when still valid
+    let mut txs = Vec::new();
+    for block in disconnect.iter().rev() {
+        for tx in block.get_transaction() {
+            if !tx.is_coinbase() {
+                txs.push(tx.clone());
+            }
+        }
+    }
+    txs.extend(inner.mempool.get_transactions());
+    inner.mempool = Mempool::new();
+    for tx in txs {
+        let txid = tx.id.clone();
+        if let Err(e) = inner.mempool.add(tx, &inner.utxo) {
+            info!("drop tx {} after the reorganization: {}", txid, e);
+        }
+    }
+    Ok(())
+}
+
+/// MoveUTXOSet disconnects the blocks of the old branch and validates and connects
+/// those of the new one, forgetting the first invalid block and its descendants.
+/// When a block can not be moved the UTXO set is brought back to the old branch
+fn move_utxo_set(utxo: &UTXOSet, disconnect: &[Block], connect: &[Block]) -> Result<()> {
+    for (index, block) in disconnect.iter().enumerate() {
+        if let Err(e) = utxo.disconnect_block(block) {
+            restore_utxo_set(utxo, &disconnect[..index], &[])?;
+            return Err(e);
+        }
+    }
+    for (index, block) in connect.iter().enumerate() {
+        if let Err(e) = utxo.validate_block(block).and_then(|_| utxo.update(block)) {
+            if e.downcast_ref::<Misbehavior>().is_some() {
+                for invalid in &connect[index..] {
+                    utxo.blockchain.remove_block(&invalid.get_hash())?;
+                }
+            }
+            restore_utxo_set(utxo, disconnect, &connect[..index])?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// RestoreUTXOSet undoes a partial move with the undo data of the blocks: the blocks
+/// of the new branch connected so far are disconnected, then those of the old branch
+/// disconnected so far are connected again
+fn restore_utxo_set(utxo: &UTXOSet, disconnected: &[Block], connected: &[Block]) -> Result<()> {
+    for block in connected.iter().rev() {
+        utxo.disconnect_block(block)?;
+    }
+    for block in disconnected.iter().rev() {
+        utxo.update(block)?;
+    }
+    Ok(())
+}
+
+fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
+    let mut data = [0; CMD_LEN];
+    for (i, d) in cmd.as_bytes().iter().enumerate() {
+        data[i] = *d;
+    }
+    data
+}
+
+/// Checksum returns the first 4 bytes of the double sha256 of the payload
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut first = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result(&mut first);
+
+    let mut second = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(&first);
+    hasher.result(&mut second);
+
+    let mut sum = [0; 4];
+    sum.copy_from_slice(&second[..4]);
+    sum
+}
+
+/// EncodeMessage frames a payload as magic | command | length | checksum | payload
+fn encode_message<T: Serialize>(cmd: &str, data: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(data)?;
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(format_err!(
+            "{} message is too large: {}",
+            cmd,
+            payload.len()
+        ));
+    }
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&params().magic);
+    bytes.extend_from_slice(&cmd_to_bytes(cmd));
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload));
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// ReadMessage reads one framed message from the stream,
+/// returning None when the peer closed the connection between two messages
+fn read_message<R: Read>(reader: &mut R) -> Result<Option<Message>> {
+    match read_frame(reader)? {
+        Some((header, payload)) => Ok(Some(decode_frame(&header, &payload)?)),
+        None => Ok(None),
+    }
+}
+
+/// ReadFrame reads the header and the payload of one message without decoding it
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<([u8; HEADER_LEN], Vec<u8>)>> {
+    let mut header = [0; HEADER_LEN];
+    let mut count = 0;
+    while count < HEADER_LEN {
+        let n = reader.read(&mut header[count..])?;
+        if n == 0 {
+            if count == 0 {
+                return Ok(None);
+            }
+            return Err(format_err!("Truncated message header: {} bytes", count));
+        }
+        count += n;
+    }
+
+    if header[..4] != params().magic {
+        return Err(Misbehavior::error(
+            100,
+            format!("Invalid network magic {:?}", &header[..4]),
+        ));
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[4 + CMD_LEN..8 + CMD_LEN]);
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(Misbehavior::error(
+            100,
+            format!("Message payload is too large: {}", len),
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((header, payload)))
+}
+
+/// DecodeFrame checks the payload of a frame and turns it into a message
+fn decode_frame(header: &[u8; HEADER_LEN], payload: &[u8]) -> Result<Message> {
+    if checksum(payload)[..] != header[8 + CMD_LEN..] {
+        return Err(Misbehavior::error(
+            20,
+            String::from("Invalid message checksum"),
+        ));
+    }
+    bytes_to_cmd(&header[4..4 + CMD_LEN], payload).map_err(|e| match e.downcast::<Misbehavior>() {
+        Ok(m) => m.into(),
+        Err(e) => Misbehavior::error(20, format!("Malformed payload: {}", e)),
+    })
+}
+
+fn bytes_to_cmd(cmd_bytes: &[u8], data: &[u8]) -> Result<Message> {
     let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
     for b in cmd_bytes {
-        if 0 as u8 != *b {
+        if 0_u8 != *b {
             cmd.push(*b)
         }
     }
-    info!("cmd: {}", String::from_utf8(cmd.clone())?);
+    info!("cmd: {}", String::from_utf8_lossy(&cmd));
 
     if cmd == "addr".as_bytes() {
         let data: Vec<String> = bincode::deserialize(data)?;
@@ -140,15 +1384,417 @@ fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
     } else if cmd == "tx".as_bytes() {
         let data: Txmsg = bincode::deserialize(data)?;
         Ok(Message::Tx(data))
+    } else if cmd == "getutxos".as_bytes() {
+        let data: GetUtxosmsg = bincode::deserialize(data)?;
+        Ok(Message::GetUtxos(data))
+    } else if cmd == "utxos".as_bytes() {
+        let data: Utxosmsg = bincode::deserialize(data)?;
+        Ok(Message::Utxos(data))
     } else if cmd == "version".as_bytes() {
         let data: Versionmsg = bincode::deserialize(data)?;
         Ok(Message::Version(data))
+    } else if cmd == "verack".as_bytes() {
+        Ok(Message::Verack)
     } else {
-        Err(format_err!("Unknown command in the server"))
+        Err(Misbehavior::error(
+            10,
+            format!("Unknown command: {}", String::from_utf8_lossy(&cmd)),
+        ))
     }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chainparams::{MAIN_PARAMS, REGTEST_PARAMS, TEST_PARAMS};
+    use crate::ed25519::Wallet;
+    use crate::testutil::{
+        funded_chain, mine, mine_mature, mine_on, mine_with_bits, misbehavior_score, new_chain,
+        pay, spendable, utxo_snapshot, TempDir,
+    };
+    use crate::txs::{TXInput, TXOutput};
 
-}
+    fn version_frame() -> Vec<u8> {
+        let msg = Versionmsg {
+            add_from: String::from("localhost:3001"),
+            version: VERSION,
+            best_height: 7,
+            user_agent: String::from(USER_AGENT),
+            services: NODE_NETWORK,
+            timestamp: now_millis(),
+        };
+        encode_message("version", &msg).unwrap()
+    }
+
+    #[test]
+    fn test_read_many_messages() {
+        let mut bytes = version_frame();
+        bytes.extend(version_frame());
+        let mut reader = &bytes[..];
+
+        for _ in 0..2 {
+            match read_message(&mut reader).unwrap() {
+                Some(Message::Version(msg)) => assert_eq!(msg.best_height, 7),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reject_malformed_frames() {
+        let frame = version_frame();
+
+        let mut bad_magic = frame.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(read_message(&mut &bad_magic[..]).is_err());
+
+        let mut bad_checksum = frame.clone();
+        let last = bad_checksum.len() - 1;
+        bad_checksum[last] ^= 0xff;
+        assert!(read_message(&mut &bad_checksum[..]).is_err());
+
+        assert!(read_message(&mut &frame[..5]).is_err());
+        assert!(read_message(&mut &frame[..frame.len() - 1]).is_err());
+
+        let unknown = encode_message("unknown", &0_u8).unwrap();
+        assert!(read_message(&mut &unknown[..]).is_err());
+
+        let mut too_large = frame;
+        too_large[4 + CMD_LEN..8 + CMD_LEN].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_message(&mut &too_large[..]).is_err());
+    }
+
+    #[test]
+    fn test_largest_block_fits_in_a_message() {
+        for params in [&MAIN_PARAMS, &TEST_PARAMS, &REGTEST_PARAMS] {
+            // room for the address of the sender in the block message
+            assert!(params.max_block_size + 1024 <= MAX_PAYLOAD_LEN);
+            assert!(params.max_tx_size < params.max_block_size);
+        }
+    }
+
+    #[test]
+    fn test_score_protocol_violations() {
+        let frame = version_frame();
+
+        let mut bad_magic = frame.clone();
+        bad_magic[0] ^= 0xff;
+        assert_eq!(
+            misbehavior_score(read_frame(&mut &bad_magic[..])),
+            Some(100)
+        );
+
+        let mut bad_checksum = frame.clone();
+        let last = bad_checksum.len() - 1;
+        bad_checksum[last] ^= 0xff;
+        let (header, payload) = read_frame(&mut &bad_checksum[..]).unwrap().unwrap();
+        assert_eq!(misbehavior_score(decode_frame(&header, &payload)), Some(20));
+
+        let unknown = encode_message("unknown", &0_u8).unwrap();
+        let (header, payload) = read_frame(&mut &unknown[..]).unwrap().unwrap();
+        assert_eq!(misbehavior_score(decode_frame(&header, &payload)), Some(10));
+
+        let garbage = encode_message("block", &0_u8).unwrap();
+        let (header, payload) = read_frame(&mut &garbage[..]).unwrap().unwrap();
+        assert_eq!(misbehavior_score(decode_frame(&header, &payload)), Some(20));
+
+        assert_eq!(misbehavior_score(read_frame(&mut &frame[..5])), None);
+    }
+
+    #[test]
+    fn test_send_through_node() {
+        let wallet = Wallet::new();
+        let from = wallet.get_address();
+        let to = Wallet::new().get_address();
+        let pub_key_hash = params().decode_address(&from).unwrap();
+        let (_dir, mut utxo) = new_chain("send");
+        mine(&mut utxo, &from, Vec::new());
+        mine_mature(&mut utxo, &to);
+
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        drop(listener);
+        let node = format!("localhost:{}", port);
+        let server = Server::new(&node, "", "", Vec::new(), utxo).unwrap();
+        let server1 = server.clone_handle();
+        thread::spawn(move || server1.start_server());
+
+        // the wallet builds the transaction from what the node lists, not from the block database
+        let mut spendable = Server::get_spendable_outputs(&node, &pub_key_hash);
+        for _ in 0..50 {
+            if spendable.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            spendable = Server::get_spendable_outputs(&node, &pub_key_hash);
+        }
+        let spendable = spendable.unwrap();
+        assert_eq!(spendable.len(), 1);
+        let tx = Transaction::new_utxo(&wallet, &to, 30, 1, spendable).unwrap();
+        Server::send_transaction(&node, &tx).unwrap();
+
+        for _ in 0..50 {
+            if server.get_mempool_tx(&tx.id).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(server.get_mempool_tx(&tx.id).is_some());
+
+        // the output spent by the mempool is not offered twice
+        let spendable = Server::get_spendable_outputs(&node, &pub_key_hash).unwrap();
+        assert!(spendable.is_empty());
+    }
+
+    /// StartNode runs a node with an empty chain on a free port and returns its address
+    fn start_node(name: &str) -> (TempDir, Server, String) {
+        let (dir, utxo) = new_chain(name);
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let node = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let server = Server::new(&node, "", "", Vec::new(), utxo).unwrap();
+        let server1 = server.clone_handle();
+        thread::spawn(move || server1.start_server());
+        (dir, server, node)
+    }
+
+    /// ConnectRaw opens a session with a node without sending anything yet
+    fn connect_raw(node: &str) -> TcpStream {
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(node) {
+                stream.set_read_timeout(Some(REQUEST_TIMEOUT)).unwrap();
+                return stream;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("node {} is not listening", node);
+    }
+
+    /// WalletVersion is the version message of a session serving nothing, which
+    /// neither gossips nor votes on the clock of the node
+    fn wallet_version(version: i32) -> Vec<u8> {
+        let msg = Versionmsg {
+            add_from: String::new(),
+            version,
+            best_height: -1,
+            user_agent: String::from(USER_AGENT),
+            services: 0,
+            timestamp: now_millis(),
+        };
+        encode_message("version", &msg).unwrap()
+    }
+
+    /// IsClosed reads until the node closes the session
+    fn is_closed(stream: &mut TcpStream) -> bool {
+        loop {
+            match read_message(stream) {
+                Ok(Some(_)) => {}
+                Ok(None) => return true,
+                Err(e) => match e.downcast_ref::<std::io::Error>() {
+                    Some(e) if e.kind() == std::io::ErrorKind::ConnectionReset => return true,
+                    _ => return false,
+                },
+            }
+        }
+    }
 
+    fn get_misbehavior(server: &Server, addr: &str) -> Option<u32> {
+        let inner = server.inner.lock().unwrap();
+        inner.peers.get(addr).map(|peer| peer.misbehavior)
+    }
+
+    #[test]
+    fn test_disconnect_incompatible_version() {
+        let (_dir, server, node) = start_node("old-version");
+        let mut stream = connect_raw(&node);
+        let addr = stream.local_addr().unwrap().to_string();
+        stream
+            .write_all(&wallet_version(MIN_PEER_VERSION - 1))
+            .unwrap();
+        assert!(is_closed(&mut stream));
+        assert!(!server.peer_is_known(&addr));
+    }
+
+    #[test]
+    fn test_score_duplicate_version() {
+        let (_dir, server, node) = start_node("duplicate-version");
+        let mut stream = connect_raw(&node);
+        let addr = stream.local_addr().unwrap().to_string();
+        stream.write_all(&wallet_version(VERSION)).unwrap();
+        loop {
+            match read_message(&mut stream).unwrap() {
+                Some(Message::Verack) => break,
+                Some(_) => {}
+                None => panic!("node closed the session"),
+            }
+        }
+        stream
+            .write_all(&encode_message("verack", &()).unwrap())
+            .unwrap();
+        for _ in 0..50 {
+            if server.peer_is_ready(&addr) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(server.peer_is_ready(&addr));
+        assert_eq!(get_misbehavior(&server, &addr), Some(0));
+
+        stream.write_all(&wallet_version(VERSION)).unwrap();
+        for _ in 0..50 {
+            if get_misbehavior(&server, &addr) != Some(0) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        // the session survives a score below the ban threshold
+        assert_eq!(get_misbehavior(&server, &addr), Some(10));
+    }
+
+    #[test]
+    fn test_reject_messages_before_handshake() {
+        let (_dir, server, node) = start_node("early-message");
+        let addr_msg = encode_message("addr", &vec![String::from("localhost:3001")]).unwrap();
+
+        // before version the session is not even registered
+        let mut stream = connect_raw(&node);
+        stream.write_all(&addr_msg).unwrap();
+        assert!(is_closed(&mut stream));
+
+        // after version, but before verack, the session is scored and closed
+        let mut stream = connect_raw(&node);
+        let addr = stream.local_addr().unwrap().to_string();
+        stream.write_all(&wallet_version(VERSION)).unwrap();
+        stream.write_all(&addr_msg).unwrap();
+        assert!(is_closed(&mut stream));
+        assert!(!server.peer_is_known(&addr));
+        assert!(server.get_peer_store().get_candidates().unwrap().is_empty());
+    }
+
+    /// FundedServer returns a node, not listening, whose wallet can spend
+    /// the coinbase of block 1
+    fn funded_server(name: &str) -> (TempDir, Server, Wallet) {
+        let (dir, utxo, wallet) = funded_chain(name, 1);
+        (
+            dir,
+            Server::new("localhost:0", "", "", Vec::new(), utxo).unwrap(),
+            wallet,
+        )
+    }
 
-// 10.13 
\ No newline at end of file
+    /// PayFromServer spends the coinbase of block 1 on the chain of the node
+    fn pay_from_server(server: &Server, wallet: &Wallet, amount: i32) -> Transaction {
+        let coin = spendable(&server.inner.lock().unwrap().utxo, wallet).remove(0);
+        pay(wallet, amount, &coin)
+    }
+
+    fn mine_branch(server: &Server, prev_hash: &str, to: &str, txs: Vec<Transaction>) -> Block {
+        mine_on(
+            &server.inner.lock().unwrap().utxo.blockchain,
+            prev_hash,
+            to,
+            txs,
+        )
+    }
+
+    fn get_tip_hash(server: &Server) -> String {
+        server.inner.lock().unwrap().utxo.blockchain.get_tip_hash()
+    }
+
+    #[test]
+    fn test_reorganize_to_longer_branch() {
+        let (_dir, server, wallet) = funded_server("reorg");
+        let (miner_a, miner_b) = (Wallet::new().get_address(), Wallet::new().get_address());
+        let fork = get_tip_hash(&server);
+        let tx = pay_from_server(&server, &wallet, 30);
+
+        let a1 = mine_branch(&server, &fork, &miner_a, vec![tx.clone()]);
+        assert!(server.connect_block(a1).unwrap());
+        // one block against one, the first seen stays the tip
+        let b1 = mine_branch(&server, &fork, &miner_b, Vec::new());
+        assert!(!server.connect_block(b1.clone()).unwrap());
+        let b2 = mine_branch(&server, &b1.get_hash(), &miner_b, Vec::new());
+        assert!(server.connect_block(b2.clone()).unwrap());
+
+        let inner = server.inner.lock().unwrap();
+        assert_eq!(inner.utxo.blockchain.get_tip_hash(), b2.get_hash());
+        // the transaction of the stale block waits in the mempool again
+        assert!(inner.mempool.contains(&tx.id));
+        // the moved UTXO set is the one of the new branch
+        let moved = utxo_snapshot(&inner.utxo);
+        inner.utxo.reindex().unwrap();
+        assert_eq!(moved, utxo_snapshot(&inner.utxo));
+    }
+
+    #[test]
+    fn test_reorganize_to_invalid_branch() {
+        let (_dir, server, wallet) = funded_server("reorg-invalid");
+        let (miner_a, miner_b) = (Wallet::new().get_address(), Wallet::new().get_address());
+        let fork = get_tip_hash(&server);
+        let tx = pay_from_server(&server, &wallet, 30);
+
+        let a1 = mine_branch(&server, &fork, &miner_a, vec![tx.clone()]);
+        assert!(server.connect_block(a1.clone()).unwrap());
+        let before = utxo_snapshot(&server.inner.lock().unwrap().utxo);
+
+        // the heavier branch spends an output that does not exist
+        let mut bogus = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: "0".repeat(64),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(10, miner_b.clone()).unwrap()],
+        };
+        bogus.id = bogus.hash().unwrap();
+        let b1 = mine_branch(&server, &fork, &miner_b, Vec::new());
+        assert!(!server.connect_block(b1.clone()).unwrap());
+        let b2 = mine_branch(&server, &b1.get_hash(), &miner_b, vec![bogus]);
+        assert_eq!(
+            misbehavior_score(server.connect_block(b2.clone())),
+            Some(100)
+        );
+
+        let inner = server.inner.lock().unwrap();
+        assert_eq!(inner.utxo.blockchain.get_tip_hash(), a1.get_hash());
+        // b1 was connected then disconnected again with its undo data
+        assert_eq!(utxo_snapshot(&inner.utxo), before);
+        assert!(inner.utxo.blockchain.get_block(&b1.get_hash()).is_ok());
+        assert!(inner.utxo.blockchain.get_block(&b2.get_hash()).is_err());
+        assert!(!inner.mempool.contains(&tx.id));
+    }
+
+    #[test]
+    fn test_connect_block_scores_broken_rules_only() {
+        let (_dir, server, _) = funded_server("connect-score");
+        let miner = Wallet::new().get_address();
+        let tip = get_tip_hash(&server);
+
+        // a block from too far in the future may be fine later
+        let block = mine_branch(&server, &tip, &miner, Vec::new());
+        let mut template = block.clone();
+        template.set_timestamp(now_millis() + params().max_future_block_time + 60_000);
+        let future = template.mine().unwrap();
+        let e = server.connect_block(future.clone()).unwrap_err();
+        assert!(e.to_string().contains("too far in the future"));
+        assert_eq!(misbehavior_score::<()>(Err(e)), None);
+        assert_eq!(get_tip_hash(&server), tip);
+
+        // a coinbase committing to another height breaks a consensus rule
+        let coinbase = Transaction::new_coinbase(miner, String::new(), 3, 0).unwrap();
+        let wrong_height = {
+            let blockchain = &server.inner.lock().unwrap().utxo.blockchain;
+            let bits = blockchain.get_next_bits(&tip).unwrap();
+            mine_with_bits(blockchain, &tip, vec![coinbase], bits)
+        };
+        assert_eq!(
+            misbehavior_score(server.connect_block(wrong_height)),
+            Some(100)
+        );
+
+        assert!(server.connect_block(block).unwrap());
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::chainparams::params;
+use crate::ed25519::Wallet;
+use crate::errors::{Misbehavior, Result};
+use crate::transaction::Transaction;
+use crate::txs::{TXOutputs, UnspentOutput};
+use crate::utxoset::UTXOSet;
+
+static DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// TempDir is an empty directory no other test uses, removed with its content when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!(
+            "blockchain-rust-{}-{}-{}",
+            name,
+            std::process::id(),
+            DIR_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// NewChain opens a chain holding only the genesis block in its own directory,
+/// the directory lives as long as the returned TempDir
+pub fn new_chain(name: &str) -> (TempDir, UTXOSet) {
+    let dir = TempDir::new(name);
+    let utxo = UTXOSet::new(Blockchain::open(&dir.path()).unwrap()).unwrap();
+    (dir, utxo)
+}
+
+/// Mine mines the transactions on the tip, paying the subsidy to the address
+pub fn mine(utxo: &mut UTXOSet, to: &str, txs: Vec<Transaction>) -> Block {
+    let height = utxo.blockchain.get_best_height().unwrap() + 1;
+    let coinbase =
+        Transaction::new_coinbase(to.to_string(), String::new(), height as usize, 0).unwrap();
+    let mut data = vec![coinbase];
+    data.extend(txs);
+    let block = utxo.blockchain.mine_block(data).unwrap();
+    utxo.update(&block).unwrap();
+    block
+}
+
+/// MineMature mines coinbase_maturity blocks, the coinbases mined before can then be spent
+pub fn mine_mature(utxo: &mut UTXOSet, to: &str) {
+    for _ in 0..params().coinbase_maturity {
+        mine(utxo, to, Vec::new());
+    }
+}
+
+/// FundedChain returns a new chain with a wallet that can spend the coinbases of its first blocks
+pub fn funded_chain(name: &str, coinbases: usize) -> (TempDir, UTXOSet, Wallet) {
+    let wallet = Wallet::new();
+    let (dir, mut utxo) = new_chain(name);
+    for _ in 0..coinbases {
+        mine(&mut utxo, &wallet.get_address(), Vec::new());
+    }
+    mine_mature(&mut utxo, &Wallet::new().get_address());
+    (dir, utxo, wallet)
+}
+
+/// Output returns an output of a transaction as an input can spend it
+pub fn output(tx: &Transaction, vout: i32) -> UnspentOutput {
+    UnspentOutput {
+        txid: tx.id.clone(),
+        vout,
+        output: tx.vout[vout as usize].clone(),
+    }
+}
+
+/// Spendable returns the outputs of the wallet that the next block can spend
+pub fn spendable(utxo: &UTXOSet, wallet: &Wallet) -> Vec<UnspentOutput> {
+    let pub_key_hash = params().decode_address(&wallet.get_address()).unwrap();
+    utxo.get_spendable_outputs(&pub_key_hash).unwrap()
+}
+
+/// Pay spends an output of the wallet to a new address, leaving a fee of 1
+pub fn pay(wallet: &Wallet, amount: i32, spent: &UnspentOutput) -> Transaction {
+    let to = Wallet::new().get_address();
+    Transaction::new_utxo(wallet, &to, amount, 1, vec![spent.clone()]).unwrap()
+}
+
+/// MisbehaviorScore returns the score of the misbehavior a result failed with, if any
+pub fn misbehavior_score<T>(result: Result<T>) -> Option<u32> {
+    result
+        .err()
+        .and_then(|e| e.downcast_ref::<Misbehavior>().map(|m| m.score))
+}
+
+/// MineOn mines a block on any stored block, without connecting it
+pub fn mine_on(blockchain: &Blockchain, prev_hash: &str, to: &str, txs: Vec<Transaction>) -> Block {
+    let height = blockchain.get_block(prev_hash).unwrap().get_height() + 1;
+    let coinbase = Transaction::new_coinbase(to.to_string(), String::new(), height, 0).unwrap();
+    let mut data = vec![coinbase];
+    data.extend(txs);
+    let bits = blockchain.get_next_bits(prev_hash).unwrap();
+    mine_with_bits(blockchain, prev_hash, data, bits)
+}
+
+/// MineWithBits mines the transactions, coinbase included, on any stored block
+/// at the given target, without connecting the block
+pub fn mine_with_bits(
+    blockchain: &Blockchain,
+    prev_hash: &str,
+    txs: Vec<Transaction>,
+    bits: u32,
+) -> Block {
+    let height = blockchain.get_block(prev_hash).unwrap().get_height() + 1;
+    let mut block = Block::new_template(txs, prev_hash.to_string(), height, bits).unwrap();
+    let min_time = blockchain.get_median_time_past(prev_hash).unwrap() + 1;
+    block.set_timestamp(block.get_timestamp().max(min_time));
+    block.mine().unwrap()
+}
+
+/// Snapshot is a UTXO set in a form that can be compared: every txid with the height
+/// and the coinbase flag of its transaction and its outputs sorted by index
+pub type Snapshot = BTreeMap<String, (usize, bool, Vec<(i32, i32, Vec<u8>)>)>;
+
+pub fn utxo_snapshot(utxo: &UTXOSet) -> Snapshot {
+    let db = sled::open(utxo.blockchain.path("utxos")).unwrap();
+    let mut snapshot = Snapshot::new();
+    for kv in db.iter() {
+        let (k, v) = kv.unwrap();
+        let outs: TXOutputs = bincode::deserialize(&v).unwrap();
+        let mut outputs: Vec<(i32, i32, Vec<u8>)> = outs
+            .outputs
+            .into_iter()
+            .map(|(vout, out)| (vout, out.value, out.pub_key_hash))
+            .collect();
+        outputs.sort();
+        snapshot.insert(
+            String::from_utf8(k.to_vec()).unwrap(),
+            (outs.height, outs.is_coinbase, outputs),
+        );
+    }
+    snapshot
+}
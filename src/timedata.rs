@@ -0,0 +1,107 @@
+use std::{collections::BTreeMap, net::IpAddr, sync::Mutex, time::SystemTime};
+
+use log::info;
+
+/// MIN_TIME_SAMPLES is how many peers must tell their time before we adjust ours
+const MIN_TIME_SAMPLES: usize = 5;
+/// MAX_TIME_SAMPLES bounds the number of peers whose time is remembered
+const MAX_TIME_SAMPLES: usize = 200;
+/// MAX_TIME_ADJUSTMENT is the largest offset, in milliseconds, applied to the local clock
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60 * 1000;
+
+/// TimeData keeps the offsets between the clocks of the peers and ours
+struct TimeData {
+    /// samples maps the ip of a peer to its clock minus ours, in milliseconds
+    samples: BTreeMap<IpAddr, i64>,
+    offset: i64,
+}
+
+impl TimeData {
+    const fn new() -> TimeData {
+        TimeData {
+            samples: BTreeMap::new(),
+            offset: 0,
+        }
+    }
+
+    /// AddSample records the offset of the clock of a peer and moves ours to the median
+    /// offset once enough peers told their time
+    fn add_sample(&mut self, ip: IpAddr, sample: i64) {
+        if self.samples.len() >= MAX_TIME_SAMPLES || self.samples.contains_key(&ip) {
+            return;
+        }
+        self.samples.insert(ip, sample);
+        if self.samples.len() < MIN_TIME_SAMPLES {
+            return;
+        }
+        let mut offsets: Vec<i64> = self.samples.values().cloned().collect();
+        offsets.sort();
+        let median = offsets[offsets.len() / 2];
+        self.offset = if median.abs() <= MAX_TIME_ADJUSTMENT {
+            median
+        } else {
+            info!(
+                "the peers are {} ms away from our clock, please check the system time",
+                median
+            );
+            0
+        };
+        info!(
+            "time offset from {} peers: {} ms",
+            offsets.len(),
+            self.offset
+        );
+    }
+}
+
+static TIME_DATA: Mutex<TimeData> = Mutex::new(TimeData::new());
+
+/// NowMillis returns the local time in milliseconds
+pub fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+/// GetAdjustedTime returns the network-adjusted time: the local time moved by
+/// the median offset of the peers
+pub fn get_adjusted_time() -> u128 {
+    let offset = TIME_DATA.lock().unwrap().offset;
+    now_millis().saturating_add_signed(offset as i128)
+}
+
+/// AddTimeSample records the time a peer sent in its version message,
+/// each ip is counted once so that one host cannot move our clock alone
+pub fn add_time_sample(ip: IpAddr, peer_time: u128) {
+    let sample =
+        (peer_time as i128 - now_millis() as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+    TIME_DATA.lock().unwrap().add_sample(ip, sample);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjusted_time() {
+        let ahead = 60 * 1000;
+        let mut data = TimeData::new();
+        for i in 0..MIN_TIME_SAMPLES {
+            assert_eq!(data.offset, 0);
+            data.add_sample(IpAddr::from([10, 0, 0, i as u8]), ahead);
+        }
+        assert_eq!(data.offset, ahead);
+        // a host cannot vote twice, whatever port it connects from
+        data.add_sample(IpAddr::from([10, 0, 0, 0]), 0);
+        assert_eq!(data.samples.len(), MIN_TIME_SAMPLES);
+        assert_eq!(data.offset, ahead);
+
+        // peers too far away leave our clock alone
+        let mut data = TimeData::new();
+        for i in 0..MIN_TIME_SAMPLES {
+            data.add_sample(IpAddr::from([10, 0, 0, i as u8]), MAX_TIME_ADJUSTMENT + 1);
+        }
+        assert_eq!(data.offset, 0);
+    }
+}
@@ -1,11 +1,8 @@
-use std::collections::HashMap;
-
 use crate::{
-    blockchain::Blockchain,
-    ed25519::{Wallet, Wallets},
+    chainparams::params,
+    ed25519::Wallet,
     errors::Result,
-    txs::{TXInput, TXOutput},
-    utxoset::UTXOSet,
+    txs::{TXInput, TXOutput, UnspentOutput},
 };
 use bincode::serialize;
 use crypto::{digest::Digest, ed25519, ripemd160::Ripemd160, sha2::Sha256};
@@ -13,6 +10,9 @@ use failure::format_err;
 use log::error;
 use serde::{Deserialize, Serialize};
 
+/// COINBASE_PREFIX_LEN is the size of the height and the extra nonce starting a coinbase input
+const COINBASE_PREFIX_LEN: usize = 16;
+
 /// Transaction present a Bitcoin transaction
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transaction {
@@ -22,48 +22,50 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    /// NewUTXOTransaction creates a new transaction
-    pub fn new_utxo(from: &str, to: &str, amount: i32, bc: &UTXOSet) -> Result<Transaction> {
+    /// NewUTXOTransaction creates a new transaction, the miner keeps the fee
+    pub fn new_utxo(
+        wallet: &Wallet,
+        to: &str,
+        amount: i32,
+        fee: i32,
+        spendable: Vec<UnspentOutput>,
+    ) -> Result<Transaction> {
+        if amount <= 0 || fee < 0 {
+            return Err(format_err!("Invalid amount {} or fee {}", amount, fee));
+        }
         let mut vin = Vec::new();
+        let mut spent = Vec::new();
 
-        let wallets = Wallets::new()?;
-
-        let wallet = match wallets.get_wallet(from) {
-            Some(w) => w,
-            None => return Err(format_err!("from wallet not found")),
+        let needed = match amount.checked_add(fee) {
+            Some(needed) => needed,
+            None => return Err(format_err!("Amount {} and fee {} overflow", amount, fee)),
         };
-
-        if let None = wallets.get_wallet(&to) {
-            return Err(format_err!("to wallet not found"));
+        let mut accumulated = 0;
+        for utxo in spendable {
+            if accumulated >= needed {
+                break;
+            }
+            accumulated += utxo.output.value;
+            vin.push(TXInput {
+                txid: utxo.txid,
+                vout: utxo.vout,
+                signature: Vec::new(),
+                pub_key: wallet.public_key.clone(),
+            });
+            spent.push(utxo.output);
         }
-
-        let mut pub_key_hash = wallet.public_key.clone();
-        hash_pub_key(&mut pub_key_hash);
-
-        let acc_v = bc.find_spendable_outputs(&pub_key_hash, amount)?;
-        if acc_v.0 < amount {
+        if accumulated < needed {
             error!("Not Enough balance");
             return Err(format_err!(
                 "Not Enough balance: current balance {}",
-                acc_v.0
+                accumulated
             ));
         }
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
-            }
-        }
 
         let mut vout = vec![TXOutput::new(amount, to.to_string())?];
 
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, from.to_string())?)
+        if accumulated > needed {
+            vout.push(TXOutput::new(accumulated - needed, wallet.get_address())?)
         }
         ///////////////////////////////////--------------////////////////////////
         let mut tx = Transaction {
@@ -73,63 +75,108 @@ impl Transaction {
         };
 
         tx.id = tx.hash()?;
-
-        bc.blockchain
-            .sign_transaction(&mut tx, &wallet.secret_key)?;
+        tx.sign(&wallet.secret_key, &spent)?;
 
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        if data == String::from("") {
+    /// NewCoinbase creates the coinbase of a block at the given height,
+    /// paying its subsidy and the fees of its transactions
+    pub fn new_coinbase(
+        to: String,
+        mut data: String,
+        height: usize,
+        fees: i32,
+    ) -> Result<Transaction> {
+        if data.is_empty() {
             data += &format!("Reward to '{}'", to);
         }
+        let reward = params().get_block_subsidy(height) + fees;
+        Transaction::new_coinbase_with_output(TXOutput::new(reward, to)?, height, data)
+    }
 
+    /// NewCoinbaseWithOutput creates a coinbase of the block at the given height paying the given output
+    pub fn new_coinbase_with_output(
+        output: TXOutput,
+        height: usize,
+        data: String,
+    ) -> Result<Transaction> {
+        // the input commits to the height so that no two coinbases have the same id,
+        // then comes the extra nonce rolled by the miner
+        let mut pub_key = (height as u64).to_le_bytes().to_vec();
+        pub_key.extend(0_u64.to_le_bytes());
+        pub_key.extend(data.as_bytes());
         let mut tx = Transaction {
             id: String::new(),
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
                 signature: Vec::new(),
-                pub_key: Vec::from(data.as_bytes()),
+                pub_key,
             }],
-            vout: vec![TXOutput::new(100, to)?],
+            vout: vec![output],
         };
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    /// GetCoinbaseHeight returns the block height a coinbase commits to
+    pub fn get_coinbase_height(&self) -> Option<usize> {
+        if !self.is_coinbase() || self.vin[0].pub_key.len() < COINBASE_PREFIX_LEN {
+            return None;
+        }
+        let mut height = [0; 8];
+        height.copy_from_slice(&self.vin[0].pub_key[..8]);
+        Some(u64::from_le_bytes(height) as usize)
+    }
+
+    /// SetExtraNonce changes the extra nonce of a coinbase and so its id
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<()> {
+        if !self.is_coinbase() || self.vin[0].pub_key.len() < COINBASE_PREFIX_LEN {
+            return Err(format_err!("tx {} has no extra nonce", self.id));
+        }
+        self.vin[0].pub_key[8..COINBASE_PREFIX_LEN].copy_from_slice(&extra_nonce.to_le_bytes());
+        self.id = self.hash()?;
+        Ok(())
+    }
+
     //// IsCoinbase checks whethet the transaction is coinbase
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
-    pub fn verify(&mut self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
+    /// CheckId checks that the id is the hash of the unsigned transaction
+    pub fn check_id(&self) -> Result<bool> {
+        let mut tx_copy = self.clone();
+        for vin in &mut tx_copy.vin {
+            vin.signature.clear();
+        }
+        Ok(tx_copy.hash()? == self.id)
+    }
+
+    /// VerifySpentOutputs checks the signatures of the inputs against the outputs
+    /// they spend, given in the same order as the inputs
+    pub fn verify_spent_outputs(&self, spent: &[TXOutput]) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
-
-        for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+        if spent.len() != self.vin.len() {
+            return Err(format_err!("ERROR: Spent outputs do not match the inputs"));
         }
 
         let mut tx_copy = self.trim_copy();
 
-        for in_id in 0..self.vin.len() {
-            let prev_tx = prev_txs.get(&self.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+        for (in_id, (vin, out)) in self.vin.iter().zip(spent).enumerate() {
+            let mut pub_key_hash = vin.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            if !out.is_locked_with_key(&pub_key_hash) {
+                return Ok(false);
+            }
+            tx_copy.vin[in_id].pub_key = out.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
 
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.vin[in_id].pub_key,
-                &self.vin[in_id].signature,
-            ) {
+            if !ed25519::verify(tx_copy.id.as_bytes(), &vin.pub_key, &vin.signature) {
                 return Ok(false);
             }
         }
@@ -137,28 +184,19 @@ impl Transaction {
         Ok(true)
     }
 
-    pub fn sign(
-        &mut self,
-        private_key: &[u8],
-        prev_txs: HashMap<String, Transaction>,
-    ) -> Result<()> {
+    pub fn sign(&mut self, private_key: &[u8], spent: &[TXOutput]) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
-
-        for vin in &self.vin {
-            if prev_txs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+        if spent.len() != self.vin.len() {
+            return Err(format_err!("ERROR: Spent outputs do not match the inputs"));
         }
+
         let mut tx_copy = self.trim_copy();
 
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
+        for (in_id, out) in spent.iter().enumerate() {
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = out.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
             let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
@@ -167,6 +205,11 @@ impl Transaction {
         Ok(())
     }
 
+    /// GetSize returns the serialized size of the transaction in bytes
+    pub fn get_size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
     pub fn hash(&mut self) -> Result<String> {
         self.id = String::new();
         let data = serialize(self)?;
@@ -182,7 +225,7 @@ impl Transaction {
         for v in &self.vin {
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
+                vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
             })
@@ -203,13 +246,28 @@ impl Transaction {
     }
 }
 
-pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
+pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
-    hasher1.input(pubKey);
-    hasher1.result(pubKey);
+    hasher1.input(pub_key);
+    hasher1.result(pub_key);
 
     let mut hasher2 = Ripemd160::new();
-    hasher2.input(pubKey);
-    pubKey.resize(20, 0);
-    hasher2.result(pubKey);
+    hasher2.input(pub_key);
+    pub_key.resize(20, 0);
+    hasher2.result(pub_key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{funded_chain, spendable};
+
+    #[test]
+    fn test_amount_overflow() {
+        let (_dir, utxo, wallet) = funded_chain("amount-overflow", 1);
+        let to = Wallet::new().get_address();
+        let e = Transaction::new_utxo(&wallet, &to, i32::MAX, 1, spendable(&utxo, &wallet))
+            .unwrap_err();
+        assert!(e.to_string().contains("overflow"));
+    }
 }
@@ -1,17 +1,48 @@
+use crate::chainparams::params;
 use crate::errors::Result;
-use crate::transaction::{hash_pub_key, Transaction};
-use bitcoincash_addr::Address;
-use clap::builder::Str;
-use failure::format_err;
 use log::debug;
-use serde::de::value;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-//TXOutputs collects TXOutput
+//TXOutputs collects the unspent TXOutput of a transaction keyed by their output index
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: HashMap<i32, TXOutput>,
+    /// height of the block that created the transaction
+    pub height: usize,
+    pub is_coinbase: bool,
+}
+
+impl TXOutputs {
+    /// IsMature checks if the outputs can be spent by a block at the given height:
+    /// coinbase outputs have to wait coinbase_maturity blocks
+    pub fn is_mature(&self, spend_height: usize) -> bool {
+        !self.is_coinbase || spend_height >= self.height + params().coinbase_maturity
+    }
+}
+
+/// SpentOutput is an output spent by a block, kept to restore it when the block is disconnected
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpentOutput {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TXOutput,
+    pub height: usize,
+    pub is_coinbase: bool,
+}
+
+/// UnspentOutput is an output a wallet can spend, with the outpoint it lives at
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnspentOutput {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TXOutput,
+}
+
+/// BlockUndo is the undo data of a block: the outputs its transactions spent
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentOutput>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -28,15 +59,6 @@ pub struct TXOutput {
     pub pub_key_hash: Vec<u8>,
 }
 
-impl TXInput {
-    /// CanUnloclOutputWith checks whether the address initiated the transaction
-    pub fn can_unlock_output_with(&self, unlocking_data: &[u8]) -> bool {
-        let mut pubkeyhash = self.pub_key.clone();
-        hash_pub_key(&mut pubkeyhash);
-        pubkeyhash == unlocking_data
-    }
-}
-
 impl TXOutput {
     // IsLockedWithkEy checks if the output can be unlocked with the provided data
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -45,7 +67,7 @@ impl TXOutput {
 
     /// Lock signs the putput
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = params().decode_address(address)?;
         debug!("lock: {}", address);
         self.pub_key_hash = pub_key_hash;
         Ok(())
@@ -60,3 +82,23 @@ impl TXOutput {
         Ok(txo)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let maturity = params().coinbase_maturity;
+        let mut outs = TXOutputs {
+            outputs: HashMap::new(),
+            height: 5,
+            is_coinbase: true,
+        };
+        assert!(!outs.is_mature(5));
+        assert!(!outs.is_mature(5 + maturity - 1));
+        assert!(outs.is_mature(5 + maturity));
+        outs.is_coinbase = false;
+        assert!(outs.is_mature(6));
+    }
+}
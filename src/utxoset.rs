@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::block::Block;
 use crate::blockchain::Blockchain;
-use crate::errors::Result;
-use crate::txs::TXOutputs;
+use crate::chainparams::params;
+use crate::errors::{Misbehavior, Result};
+use crate::txs::{BlockUndo, SpentOutput, TXOutput, TXOutputs, UnspentOutput};
 use bincode::deserialize;
+use failure::format_err;
 use log::info;
 
 /// UTXOSet represents UTXO set
@@ -13,41 +15,227 @@ pub struct UTXOSet {
 }
 
 impl UTXOSet {
-    /// Reindex rebuilds the UTXO set
+    /// New opens the UTXO set of the blockchain, a missing one is built from its blocks
+    pub fn new(blockchain: Blockchain) -> Result<UTXOSet> {
+        let utxo_set = UTXOSet { blockchain };
+        if utxo_set.count_transaction()? == 0 {
+            utxo_set.reindex()?;
+        }
+        Ok(utxo_set)
+    }
+
+    /// Reindex rebuilds the UTXO set by validating and connecting the blocks
+    /// of the main chain from the genesis block
     pub fn reindex(&self) -> Result<()> {
-        if let Err(e) = std::fs::remove_dir_all("data/utxos") {
+        if std::fs::remove_dir_all(self.blockchain.path("utxos")).is_err() {
             info!("not exist any utxos to delete")
         }
 
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.blockchain.path("utxos"))?;
+
+        let mut hashes = self.blockchain.get_block_hashes()?;
+        hashes.reverse();
+        for hash in hashes {
+            let block = self.blockchain.get_block(&hash)?;
+            self.validate_block_in(&db, &block)?;
+            self.update_in(&db, &block)?;
+        }
+
+        Ok(())
+    }
 
-        let utxos = self.blockchain.find_utxo();
+    /// ValidateBlock fully checks a block before it is connected on top of the UTXO set:
+    /// the rules of Blockchain::check_block, a single leading coinbase with a correct
+    /// reward, the transaction ids and signatures, and no double spends.
+    /// A broken rule is returned as a Misbehavior
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        let db = sled::open(self.blockchain.path("utxos"))?;
+        self.validate_block_in(&db, block)
+    }
 
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
+    fn validate_block_in(&self, db: &sled::Db, block: &Block) -> Result<()> {
+        self.blockchain.check_block(block)?;
+        let hash = block.get_hash();
+        let txs = block.get_transaction();
+        match txs.first() {
+            Some(tx) if tx.is_coinbase() => {}
+            _ => {
+                return Err(Misbehavior::error(
+                    100,
+                    format!("Block {} does not start with a coinbase", hash),
+                ))
+            }
+        }
+        if txs[0].get_coinbase_height() != Some(block.get_height()) {
+            return Err(Misbehavior::error(
+                100,
+                format!(
+                    "Block {} has a coinbase not committing to its height {}",
+                    hash,
+                    block.get_height()
+                ),
+            ));
         }
 
+        // outputs created by a transaction of the block can be spent by the next ones,
+        // unless they come from the coinbase
+        let mut created: HashMap<(String, i32), (TXOutput, bool)> = HashMap::new();
+        let mut spent_in_block = HashSet::new();
+        let mut txids = HashSet::new();
+        let mut fees: i64 = 0;
+        for (index, tx) in txs.iter().enumerate() {
+            if !tx.check_id()? {
+                return Err(Misbehavior::error(
+                    100,
+                    format!("Block {} has tx {} with a wrong id", hash, tx.id),
+                ));
+            }
+            // a txid may only come back once all the outputs of the earlier one are spent
+            if db.contains_key(&tx.id)? || !txids.insert(tx.id.clone()) {
+                return Err(Misbehavior::error(
+                    100,
+                    format!(
+                        "Block {} has tx {} whose earlier outputs are still unspent",
+                        hash, tx.id
+                    ),
+                ));
+            }
+            // a coinbase pays nothing once the max supply is reached
+            let min_value = if tx.is_coinbase() { 0 } else { 1 };
+            if tx.vout.is_empty() || tx.vout.iter().any(|out| out.value < min_value) {
+                return Err(Misbehavior::error(
+                    100,
+                    format!(
+                        "Block {} has tx {} with no or non positive outputs",
+                        hash, tx.id
+                    ),
+                ));
+            }
+            let output_value: i64 = tx.vout.iter().map(|out| out.value as i64).sum();
+
+            if tx.is_coinbase() {
+                if index > 0 {
+                    return Err(Misbehavior::error(
+                        100,
+                        format!("Block {} has more than one coinbase", hash),
+                    ));
+                }
+            } else {
+                let mut spent = Vec::new();
+                for vin in &tx.vin {
+                    let outpoint = (vin.txid.clone(), vin.vout);
+                    if !spent_in_block.insert(outpoint.clone()) {
+                        return Err(Misbehavior::error(
+                            100,
+                            format!("Block {} spends {}:{} twice", hash, vin.txid, vin.vout),
+                        ));
+                    }
+                    let (output, mature) = match created.remove(&outpoint) {
+                        Some((out, is_coinbase)) => (out, !is_coinbase),
+                        None => match get_output_in(db, &vin.txid, vin.vout, block.get_height())? {
+                            Some(coin) => coin,
+                            None => {
+                                return Err(Misbehavior::error(
+                                    100,
+                                    format!(
+                                        "Block {} spends {}:{} which is not in the UTXO set",
+                                        hash, vin.txid, vin.vout
+                                    ),
+                                ))
+                            }
+                        },
+                    };
+                    if !mature {
+                        return Err(Misbehavior::error(
+                            100,
+                            format!(
+                                "Block {} spends {}:{} which is an immature coinbase output",
+                                hash, vin.txid, vin.vout
+                            ),
+                        ));
+                    }
+                    spent.push(output);
+                }
+                let input_value: i64 = spent.iter().map(|out| out.value as i64).sum();
+                if tx.vin.is_empty() || output_value > input_value {
+                    return Err(Misbehavior::error(
+                        100,
+                        format!(
+                            "Block {} has tx {} spending {} with {} in its inputs",
+                            hash, tx.id, output_value, input_value
+                        ),
+                    ));
+                }
+                if !tx.verify_spent_outputs(&spent)? {
+                    return Err(Misbehavior::error(
+                        100,
+                        format!("Block {} has tx {} with an invalid signature", hash, tx.id),
+                    ));
+                }
+                fees += input_value - output_value;
+            }
+
+            for (out_idx, out) in tx.vout.iter().enumerate() {
+                created.insert(
+                    (tx.id.clone(), out_idx as i32),
+                    (out.clone(), tx.is_coinbase()),
+                );
+            }
+        }
+
+        // the coinbase may claim the subsidy and the fees of the block
+        let reward: i64 = txs[0].vout.iter().map(|out| out.value as i64).sum();
+        let allowed = params().get_block_subsidy(block.get_height()) as i64 + fees;
+        if reward > allowed {
+            return Err(Misbehavior::error(
+                100,
+                format!(
+                    "Block {} has a coinbase paying {} instead of at most {}",
+                    hash, reward, allowed
+                ),
+            ));
+        }
         Ok(())
     }
 
     /// Update updates the UTXO set with transactions from the Block
+    /// and records the outputs it spends as the undo data of the block
     ///
     /// The Block is consedered to be the tip of a blockchain
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.blockchain.path("utxos"))?;
+        self.update_in(&db, block)
+    }
+
+    fn update_in(&self, db: &sled::Db, block: &Block) -> Result<()> {
+        let mut undo = BlockUndo::default();
 
         for tx in block.get_transaction() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
+                    let mut update_outputs: TXOutputs = match db.get(&vin.txid)? {
+                        Some(outs) => deserialize(&outs)?,
+                        None => {
+                            return Err(format_err!("Output {} is not in the UTXO set", vin.txid))
+                        }
                     };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
+                    let output = match update_outputs.outputs.remove(&vin.vout) {
+                        Some(output) => output,
+                        None => {
+                            return Err(format_err!(
+                                "Output {}:{} is not in the UTXO set",
+                                vin.txid,
+                                vin.vout
+                            ))
                         }
-                    }
+                    };
+                    undo.spent.push(SpentOutput {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                        output,
+                        height: update_outputs.height,
+                        is_coinbase: update_outputs.is_coinbase,
+                    });
 
                     if update_outputs.outputs.is_empty() {
                         db.remove(&vin.txid)?;
@@ -58,72 +246,127 @@ impl UTXOSet {
             }
 
             let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
+                outputs: HashMap::new(),
+                height: block.get_height(),
+                is_coinbase: tx.is_coinbase(),
             };
 
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            for (out_idx, out) in tx.vout.iter().enumerate() {
+                new_outputs.outputs.insert(out_idx as i32, out.clone());
             }
 
             db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
         }
+        self.blockchain.put_undo(&block.get_hash(), &undo)?;
         Ok(())
     }
 
-    /// FindUnspentTransactions returns a list of transactions containing unspent outputs
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated: i32 = 0;
-        let db = sled::open("data/utxos")?;
+    /// DisconnectBlock undoes Update: it removes the outputs created by the Block
+    /// and restores the outputs it spent from its undo data
+    ///
+    /// The Block is consedered to be the tip of the UTXO set
+    pub fn disconnect_block(&self, block: &Block) -> Result<()> {
+        let undo = match self.blockchain.get_undo(&block.get_hash())? {
+            Some(undo) => undo,
+            None => return Err(format_err!("Block {} has no undo data", block.get_hash())),
+        };
+        let db = sled::open(self.blockchain.path("utxos"))?;
+
+        let mut created = HashSet::new();
+        for tx in block.get_transaction() {
+            db.remove(&tx.id)?;
+            created.insert(tx.id.clone());
+        }
+        for spent in undo.spent {
+            // outputs created and spent inside the block are gone with it
+            if created.contains(&spent.txid) {
+                continue;
+            }
+            let mut outputs: TXOutputs = match db.get(&spent.txid)? {
+                Some(outs) => deserialize(&outs)?,
+                None => TXOutputs {
+                    outputs: HashMap::new(),
+                    height: spent.height,
+                    is_coinbase: spent.is_coinbase,
+                },
+            };
+            outputs.outputs.insert(spent.vout, spent.output);
+            db.insert(spent.txid.as_bytes(), bincode::serialize(&outputs)?)?;
+        }
+        Ok(())
+    }
+
+    /// GetSpendableOutputs returns the outputs of a public key hash that can be spent
+    /// by the next block
+    pub fn get_spendable_outputs(&self, pub_key_hash: &[u8]) -> Result<Vec<UnspentOutput>> {
+        let mut spendable = Vec::new();
+        let next_height = (self.blockchain.get_best_height()? + 1) as usize;
+        let db = sled::open(self.blockchain.path("utxos"))?;
 
         for kv in db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            if !outs.is_mature(next_height) {
+                continue;
+            }
 
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+            for (vout, output) in outs.outputs {
+                if output.is_locked_with_key(pub_key_hash) {
+                    spendable.push(UnspentOutput {
+                        txid: txid.clone(),
+                        vout,
+                        output,
+                    });
                 }
             }
         }
 
-        Ok((accumulated, unspent_outputs))
+        Ok(spendable)
     }
 
-    // FindUTXO finds UTXO for a piblic key hash
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open("data/utxos")?;
+    /// GetBalance returns the coins of a public key hash that the next block can spend
+    /// and those still locked in immature coinbase outputs
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> Result<(i64, i64)> {
+        let next_height = (self.blockchain.get_best_height()? + 1) as usize;
+        let db = sled::open(self.blockchain.path("utxos"))?;
+        let mut spendable = 0;
+        let mut immature = 0;
 
         for kv in db.iter() {
             let (_, v) = kv?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            let mature = outs.is_mature(next_height);
+            for out in outs.outputs.values() {
+                if !out.is_locked_with_key(pub_key_hash) {
+                    continue;
+                }
+                if mature {
+                    spendable += out.value as i64;
+                } else {
+                    immature += out.value as i64;
                 }
             }
         }
-        Ok(utxos)
+        Ok((spendable, immature))
+    }
+
+    /// GetOutput returns the output spent by the given input if it is still unspent,
+    /// and whether a block at the given height may spend it
+    pub fn get_output(
+        &self,
+        txid: &str,
+        vout: i32,
+        spend_height: usize,
+    ) -> Result<Option<(TXOutput, bool)>> {
+        let db = sled::open(self.blockchain.path("utxos"))?;
+        get_output_in(&db, txid, vout, spend_height)
     }
 
     /// Count Transaction returns the number of transactions in the UTXO set
     pub fn count_transaction(&self) -> Result<i32> {
         let mut counter = 0;
-        let db = sled::open("data/utxos")?;
+        let db = sled::open(self.blockchain.path("utxos"))?;
         for kv in db.iter() {
             kv?;
             counter += 1;
@@ -132,3 +375,198 @@ impl UTXOSet {
         Ok(counter)
     }
 }
+
+fn get_output_in(
+    db: &sled::Db,
+    txid: &str,
+    vout: i32,
+    spend_height: usize,
+) -> Result<Option<(TXOutput, bool)>> {
+    let outs: TXOutputs = match db.get(txid)? {
+        Some(v) => bincode::deserialize(&v)?,
+        None => return Ok(None),
+    };
+    let mature = outs.is_mature(spend_height);
+    Ok(outs.outputs.get(&vout).map(|out| (out.clone(), mature)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ed25519::Wallet;
+    use crate::testutil::{
+        funded_chain, mine, mine_mature, mine_on, mine_with_bits, misbehavior_score, new_chain,
+        output, pay, spendable, utxo_snapshot,
+    };
+    use crate::transaction::Transaction;
+    use crate::txs::TXOutput;
+
+    #[test]
+    fn test_disconnect_block() {
+        let (w1, w2, w3) = (Wallet::new(), Wallet::new(), Wallet::new());
+        let (_dir, mut utxo) = new_chain("disconnect");
+        let cb1 = mine(&mut utxo, &w1.get_address(), Vec::new()).get_transaction()[0].clone();
+        let cb2 = mine(&mut utxo, &w3.get_address(), Vec::new()).get_transaction()[0].clone();
+        mine_mature(&mut utxo, &w2.get_address());
+        let t0 =
+            Transaction::new_utxo(&w1, &w2.get_address(), 40, 0, vec![output(&cb1, 0)]).unwrap();
+        mine(&mut utxo, &w2.get_address(), vec![t0.clone()]);
+        let before = utxo_snapshot(&utxo);
+
+        // t1 spends one of the two outputs of t0, t2 spends an output created in the
+        // same block and t3 spends the whole coinbase of block 2
+        let t1 =
+            Transaction::new_utxo(&w2, &w3.get_address(), 25, 0, vec![output(&t0, 0)]).unwrap();
+        let t2 =
+            Transaction::new_utxo(&w3, &w1.get_address(), 20, 0, vec![output(&t1, 0)]).unwrap();
+        let t3 =
+            Transaction::new_utxo(&w3, &w2.get_address(), 50, 0, vec![output(&cb2, 0)]).unwrap();
+        let tip = utxo.blockchain.get_tip_hash();
+        let block = mine_on(&utxo.blockchain, &tip, &w2.get_address(), vec![t1, t2, t3]);
+        utxo.validate_block(&block).unwrap();
+        utxo.blockchain.add_block(block.clone()).unwrap();
+        utxo.update(&block).unwrap();
+        assert_ne!(utxo_snapshot(&utxo), before);
+
+        // the outputs come back with the height and the coinbase flag of their transaction
+        utxo.disconnect_block(&block).unwrap();
+        let after = utxo_snapshot(&utxo);
+        assert_eq!(after[&cb2.id].0, 2);
+        assert!(after[&cb2.id].1);
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_reject_wrong_coinbase_height() {
+        let (_dir, mut utxo) = new_chain("coinbase-height");
+        let address = Wallet::new().get_address();
+        mine(&mut utxo, &address, Vec::new());
+        let tip = utxo.blockchain.get_tip_hash();
+        let height = utxo.blockchain.get_best_height().unwrap() as usize + 1;
+
+        // the coinbase commits to the height of the block after this one
+        let coinbase = Transaction::new_coinbase(address, String::new(), height + 1, 0).unwrap();
+        let bits = utxo.blockchain.get_next_bits(&tip).unwrap();
+        let block = mine_with_bits(&utxo.blockchain, &tip, vec![coinbase], bits);
+
+        let e = utxo.validate_block(&block).unwrap_err();
+        assert!(e.to_string().contains("not committing to its height"));
+    }
+
+    #[test]
+    fn test_reject_duplicate_txid() {
+        let (_dir, mut utxo, wallet) = funded_chain("duplicate-txid", 1);
+        let to = Wallet::new().get_address();
+        let coin = spendable(&utxo, &wallet).remove(0);
+        let tx = Transaction::new_utxo(&wallet, &to, 30, 0, vec![coin]).unwrap();
+        let miner = Wallet::new().get_address();
+
+        // twice in a block
+        let tip = utxo.blockchain.get_tip_hash();
+        let block = mine_on(&utxo.blockchain, &tip, &miner, vec![tx.clone(), tx.clone()]);
+        let e = utxo.validate_block(&block).unwrap_err();
+        assert!(e.to_string().contains("still unspent"));
+
+        // again once its outputs are in the UTXO set
+        mine(&mut utxo, &miner, vec![tx.clone()]);
+        let tip = utxo.blockchain.get_tip_hash();
+        let block = mine_on(&utxo.blockchain, &tip, &miner, vec![tx]);
+        let e = utxo.validate_block(&block).unwrap_err();
+        assert!(e.to_string().contains("still unspent"));
+    }
+
+    /// BlockWithReward mines the transactions on the tip after a coinbase paying the given value
+    fn block_with_reward(utxo: &UTXOSet, txs: Vec<Transaction>, reward: i32) -> Block {
+        let tip = utxo.blockchain.get_tip_hash();
+        let height = utxo.blockchain.get_best_height().unwrap() as usize + 1;
+        let output = TXOutput::new(reward, Wallet::new().get_address()).unwrap();
+        let coinbase =
+            Transaction::new_coinbase_with_output(output, height, String::new()).unwrap();
+        let mut data = vec![coinbase];
+        data.extend(txs);
+        let bits = utxo.blockchain.get_next_bits(&tip).unwrap();
+        mine_with_bits(&utxo.blockchain, &tip, data, bits)
+    }
+
+    #[test]
+    fn test_coinbase_reward() {
+        let (_dir, utxo, wallet) = funded_chain("coinbase-reward", 1);
+        let coin = spendable(&utxo, &wallet).remove(0);
+        let tx = Transaction::new_utxo(&wallet, &Wallet::new().get_address(), 30, 5, vec![coin])
+            .unwrap();
+        let height = utxo.blockchain.get_best_height().unwrap() as usize + 1;
+        let allowed = params().get_block_subsidy(height) + 5;
+
+        let block = block_with_reward(&utxo, vec![tx.clone()], allowed + 1);
+        let e = utxo.validate_block(&block).unwrap_err();
+        assert!(e.to_string().contains("coinbase paying"));
+
+        let block = block_with_reward(&utxo, vec![tx], allowed);
+        utxo.validate_block(&block).unwrap();
+    }
+
+    /// CheckRejected checks that validate_block scores the block and tells why
+    fn check_rejected(utxo: &UTXOSet, block: &Block, reason: &str) {
+        let e = utxo.validate_block(block).unwrap_err();
+        assert!(e.to_string().contains(reason), "{}", e);
+        assert_eq!(misbehavior_score::<()>(Err(e)), Some(100));
+    }
+
+    #[test]
+    fn test_reject_invalid_block_transactions() {
+        let (_dir, utxo, wallet) = funded_chain("invalid-block-txs", 1);
+        let coin = spendable(&utxo, &wallet).remove(0);
+        let tip = utxo.blockchain.get_tip_hash();
+        let height = utxo.blockchain.get_best_height().unwrap() as usize + 1;
+        let bits = utxo.blockchain.get_next_bits(&tip).unwrap();
+        let miner = Wallet::new().get_address();
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new(), height, 0).unwrap();
+        let tx = pay(&wallet, 30, &coin);
+
+        let txs = vec![tx.clone(), coinbase.clone()];
+        let block = mine_with_bits(&utxo.blockchain, &tip, txs, bits);
+        check_rejected(&utxo, &block, "does not start with a coinbase");
+
+        let other = Transaction::new_coinbase(miner, String::from("other"), height, 0).unwrap();
+        let txs = vec![coinbase.clone(), other];
+        let block = mine_with_bits(&utxo.blockchain, &tip, txs, bits);
+        check_rejected(&utxo, &block, "more than one coinbase");
+
+        let conflict = pay(&wallet, 40, &coin);
+        let txs = vec![coinbase.clone(), tx.clone(), conflict];
+        let block = mine_with_bits(&utxo.blockchain, &tip, txs, bits);
+        check_rejected(&utxo, &block, "twice");
+
+        let mut tampered = tx;
+        tampered.vin[0].signature[0] ^= 0xff;
+        let txs = vec![coinbase.clone(), tampered];
+        let block = mine_with_bits(&utxo.blockchain, &tip, txs, bits);
+        check_rejected(&utxo, &block, "invalid signature");
+
+        let stolen = pay(&Wallet::new(), 30, &coin);
+        let block = mine_with_bits(&utxo.blockchain, &tip, vec![coinbase, stolen], bits);
+        check_rejected(&utxo, &block, "invalid signature");
+    }
+
+    #[test]
+    fn test_spend_coinbase_once_mature() {
+        let (_dir, mut utxo) = new_chain("utxo-maturity");
+        let wallet = Wallet::new();
+        let miner = Wallet::new().get_address();
+        let block = mine(&mut utxo, &wallet.get_address(), Vec::new());
+        let tx = pay(&wallet, 30, &output(&block.get_transaction()[0], 0));
+        for _ in 2..params().coinbase_maturity {
+            mine(&mut utxo, &miner, Vec::new());
+        }
+
+        // the next block comes one block too early
+        let tip = utxo.blockchain.get_tip_hash();
+        let block = mine_on(&utxo.blockchain, &tip, &miner, vec![tx.clone()]);
+        check_rejected(&utxo, &block, "immature coinbase output");
+
+        mine(&mut utxo, &miner, Vec::new());
+        let tip = utxo.blockchain.get_tip_hash();
+        let block = mine_on(&utxo.blockchain, &tip, &miner, vec![tx]);
+        utxo.validate_block(&block).unwrap();
+    }
+}